@@ -0,0 +1,183 @@
+// Pluggable sources for the environment and the library settings used by `Config`
+
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    env,
+    hash::{BuildHasher, Hash},
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{metadata, Error, InternalLib, InternalPkgConfig, Library, ProbeErrorKind, Source};
+
+/// Source of the environment variables read by [crate::Config].
+///
+/// The process environment is used by default. A `HashMap` from variable names
+/// to values can be used instead to run `system-deps` against a controlled environment.
+pub trait EnvSource: Send + Sync {
+    /// Return the value of the variable `name`, or `None` if it is not defined
+    fn var(&self, name: &str) -> Option<String>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// [EnvSource] reading the environment of the current process
+pub struct ProcessEnv;
+
+impl EnvSource for ProcessEnv {
+    fn var(&self, name: &str) -> Option<String> {
+        env::var(name).ok()
+    }
+}
+
+impl<K, S> EnvSource for HashMap<K, String, S>
+where
+    K: Borrow<str> + Hash + Eq + Send + Sync,
+    S: BuildHasher + Send + Sync,
+{
+    fn var(&self, name: &str) -> Option<String> {
+        self.get(name).cloned()
+    }
+}
+
+/// Backend used by [crate::Config] to retrieve the settings of a library.
+///
+/// [PkgConfigBackend] is used by default. [FakeBackend] can be used to test build
+/// scripts without having the libraries installed.
+pub trait ProbeBackend: Send + Sync {
+    /// Look for the library `name` with a version matching `version`,
+    /// using [the version format](index.html#version-format).
+    ///
+    /// `statik` is `true` if the library is going to be statically linked.
+    /// `env` is the environment `Config` has been configured with, see [crate::Config::env_source].
+    /// Fallback names are handled by `Config`, which calls this method for each of them.
+    fn probe(
+        &self,
+        name: &str,
+        version: &str,
+        statik: bool,
+        env: &dyn EnvSource,
+    ) -> Result<Library, Error>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// [ProbeBackend] running `pkg-config`
+pub struct PkgConfigBackend;
+
+impl ProbeBackend for PkgConfigBackend {
+    fn probe(
+        &self,
+        name: &str,
+        version: &str,
+        statik: bool,
+        env: &dyn EnvSource,
+    ) -> Result<Library, Error> {
+        let pkg_config = InternalPkgConfig::new();
+        pkg_config
+            .probe_range(name, metadata::parse_version(version), statik, env)
+            .map_err(|e| Error::pkg_config(name, version, e, &pkg_config, env))
+    }
+}
+
+// [PkgConfigBackend] also looking into the directories of the libraries built internally,
+// which are added to the `PKG_CONFIG_PATH` of the `pkg-config` process
+pub(crate) struct InternalDirsBackend(pub(crate) InternalPkgConfig);
+
+impl ProbeBackend for InternalDirsBackend {
+    fn probe(
+        &self,
+        name: &str,
+        version: &str,
+        statik: bool,
+        env: &dyn EnvSource,
+    ) -> Result<Library, Error> {
+        self.0
+            .probe_range(name, metadata::parse_version(version), statik, env)
+            .map_err(|e| Error::pkg_config(name, version, e, &self.0, env))
+    }
+}
+
+#[derive(Debug, Default)]
+/// In-memory [ProbeBackend] returning the libraries it has been given.
+///
+/// ```
+/// let backend = system_deps::FakeBackend::new().library("testlib", "1.2.3");
+/// let config = system_deps::Config::new().probe_backend(backend);
+/// ```
+pub struct FakeBackend {
+    libs: HashMap<String, Library>,
+}
+
+impl FakeBackend {
+    /// Create a backend without any library
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Define the library `name` with version `version`, linking with `-l{name}`
+    pub fn library(self, name: &str, version: &str) -> Self {
+        let mut lib = Library::from_env_variables(name);
+        lib.source = Source::PkgConfig;
+        lib.libs = vec![InternalLib::new(name.to_string(), false)];
+        lib.version = version.to_string();
+        self.add_library(lib)
+    }
+
+    /// Define a library with all its settings, using [Library::name] as the name it is found under
+    pub fn add_library(mut self, library: Library) -> Self {
+        self.libs.insert(library.name.clone(), library);
+        self
+    }
+}
+
+impl ProbeBackend for FakeBackend {
+    fn probe(
+        &self,
+        name: &str,
+        version: &str,
+        statik: bool,
+        _env: &dyn EnvSource,
+    ) -> Result<Library, Error> {
+        let failure = |kind, cause| Error::ProbeFailure {
+            key: None,
+            name: name.to_string(),
+            version: version.to_string(),
+            kind,
+            cause,
+        };
+        let lib = self.libs.get(name).ok_or_else(|| {
+            failure(
+                ProbeErrorKind::NotFound,
+                format!("Package {} was not found", name),
+            )
+        })?;
+
+        if !version_matches(&lib.version, version) {
+            return Err(failure(
+                ProbeErrorKind::WrongVersion,
+                format!(
+                    "Requested '{}' but version of {} is {}",
+                    version, name, lib.version
+                ),
+            ));
+        }
+
+        let mut lib = lib.clone();
+        lib.statik = statik;
+        Ok(lib)
+    }
+}
+
+fn version_matches(version: &str, range: &str) -> bool {
+    let range = metadata::parse_version(range);
+    let lt = |a: &str, b: &str| version_compare::compare(a, b) == Ok(version_compare::Cmp::Lt);
+
+    let above_min = match range.start_bound() {
+        Bound::Included(min) => !lt(version, min),
+        _ => true,
+    };
+    let below_max = match range.end_bound() {
+        Bound::Excluded(max) => lt(version, max),
+        _ => true,
+    };
+    above_min && below_max
+}
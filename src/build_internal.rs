@@ -0,0 +1,434 @@
+//! Helpers building a library from its sources, to be used in the closures
+//! registered using [Config::add_build_internal_with](crate::Config::add_build_internal_with).
+//!
+//! Each builder configures the sources in `OUT_DIR`, builds and installs them into a
+//! prefix also located in `OUT_DIR`, then looks for the library using the `.pc` files it installed.
+//! The library is built as a static library, using the C compiler defined by `CC_$TARGET`,
+//! `TARGET_CC` or `CC` if any, and configured for cross-compilation if `TARGET` differs from `HOST`.
+//! When cross-compiling with Meson, the compiler defaults to `$TARGET-gcc` without the vendor
+//! part of the target, such as `aarch64-linux-gnu-gcc`.
+//! Custom targets defined by a JSON file are passed as is to `configure --host` and configure a
+//! `Generic` CMake system, while Meson builds fail as their cross file cannot be written.
+//!
+//! The builders are available through the `meson`, `cmake` and `autotools` cargo features.
+//!
+//! ```no_run
+//! # #[cfg(feature = "meson")]
+//! fn main() {
+//!     system_deps::Config::new()
+//!         .add_build_internal_with("testlib", |ctx| {
+//!             system_deps::build_internal::meson("vendor/testlib").build(ctx)
+//!         })
+//!         .probe()
+//!         .unwrap();
+//! }
+//! # #[cfg(not(feature = "meson"))]
+//! # fn main() {}
+//! ```
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use cfg_expr::targets::TargetInfo;
+
+use crate::{
+    pc_files, BuildInternalClosureError, BuildInternalContext, InternalPkgConfig, Library,
+};
+
+#[cfg(feature = "meson")]
+/// Build the library from the Meson project in `src_dir`
+pub fn meson<P: AsRef<Path>>(src_dir: P) -> Meson {
+    Meson {
+        src_dir: src_dir.as_ref().to_path_buf(),
+        options: Vec::new(),
+    }
+}
+
+#[cfg(feature = "cmake")]
+/// Build the library from the CMake project in `src_dir`
+pub fn cmake<P: AsRef<Path>>(src_dir: P) -> CMake {
+    CMake {
+        src_dir: src_dir.as_ref().to_path_buf(),
+        defines: Vec::new(),
+    }
+}
+
+#[cfg(feature = "autotools")]
+/// Build the library from the Autotools project in `src_dir`, which should contain a `configure` script
+pub fn autotools<P: AsRef<Path>>(src_dir: P) -> Autotools {
+    Autotools {
+        src_dir: src_dir.as_ref().to_path_buf(),
+        args: Vec::new(),
+    }
+}
+
+#[cfg(feature = "meson")]
+#[derive(Debug, Clone)]
+/// Builder for Meson projects, see [meson()]
+pub struct Meson {
+    src_dir: PathBuf,
+    options: Vec<(String, String)>,
+}
+
+#[cfg(feature = "meson")]
+impl Meson {
+    /// Set the project option `name` to `value`, passed as `-D{name}={value}` to `meson setup`
+    pub fn option(mut self, name: &str, value: &str) -> Self {
+        self.options.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Configure, build and install the project then return the library it provides
+    pub fn build(
+        &self,
+        ctx: &BuildInternalContext<'_>,
+    ) -> Result<Library, BuildInternalClosureError> {
+        let dirs = Dirs::new(ctx)?;
+
+        let mut setup = Command::new("meson");
+        setup
+            .arg("setup")
+            .arg(&dirs.build)
+            .arg(absolute(&self.src_dir)?)
+            .arg(format!("--prefix={}", dirs.prefix.display()))
+            .arg("--libdir=lib")
+            .arg("--buildtype=release")
+            .arg("--default-library=static");
+        if let Some((triple, target)) = cross_target(ctx) {
+            let target = target.ok_or_else(|| {
+                failed(&format!(
+                    "cannot write a Meson cross file for the custom target {}",
+                    triple
+                ))
+            })?;
+            let cross_file = dirs.root.join("cross.ini");
+            fs::write(&cross_file, meson_cross_file(target, &cross_compiler(ctx)))
+                .map_err(|e| failed(&format!("failed to write {}: {}", cross_file.display(), e)))?;
+            setup.arg(format!("--cross-file={}", cross_file.display()));
+        } else if let Some(cc) = compiler(ctx) {
+            setup.env("CC", cc);
+        }
+        for (name, value) in &self.options {
+            setup.arg(format!("-D{}={}", name, value));
+        }
+        set_pkg_config_path(&mut setup, ctx)?;
+        // Start from a clean build directory as `meson setup` fails on an existing one
+        let _ = fs::remove_dir_all(&dirs.build);
+        run(&mut setup)?;
+
+        run(Command::new("meson")
+            .args(["compile", "-C"])
+            .arg(&dirs.build)
+            .arg(format!("-j{}", jobs(ctx))))?;
+        run(Command::new("meson")
+            .args(["install", "-C"])
+            .arg(&dirs.build))?;
+
+        dirs.probe(ctx)
+    }
+}
+
+#[cfg(feature = "cmake")]
+#[derive(Debug, Clone)]
+/// Builder for CMake projects, see [cmake()]
+pub struct CMake {
+    src_dir: PathBuf,
+    defines: Vec<(String, String)>,
+}
+
+#[cfg(feature = "cmake")]
+impl CMake {
+    /// Define the cache entry `name` as `value`, passed as `-D{name}={value}` to `cmake`
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Configure, build and install the project then return the library it provides
+    pub fn build(
+        &self,
+        ctx: &BuildInternalContext<'_>,
+    ) -> Result<Library, BuildInternalClosureError> {
+        let dirs = Dirs::new(ctx)?;
+
+        let mut configure = Command::new("cmake");
+        configure
+            .arg("-S")
+            .arg(absolute(&self.src_dir)?)
+            .arg("-B")
+            .arg(&dirs.build)
+            .arg(format!("-DCMAKE_INSTALL_PREFIX={}", dirs.prefix.display()))
+            .arg("-DCMAKE_INSTALL_LIBDIR=lib")
+            .arg("-DCMAKE_BUILD_TYPE=Release")
+            .arg("-DBUILD_SHARED_LIBS=OFF");
+        if let Some(cc) = compiler(ctx) {
+            configure.arg(format!("-DCMAKE_C_COMPILER={}", cc));
+        }
+        match cross_target(ctx) {
+            Some((_, Some(target))) => {
+                configure.args(cmake_cross_defines(target));
+            }
+            Some((triple, None)) => {
+                let arch = triple.split('-').next().unwrap_or(triple);
+                configure
+                    .arg("-DCMAKE_SYSTEM_NAME=Generic")
+                    .arg(format!("-DCMAKE_SYSTEM_PROCESSOR={}", arch));
+            }
+            None => {}
+        }
+        for (name, value) in &self.defines {
+            configure.arg(format!("-D{}={}", name, value));
+        }
+        set_pkg_config_path(&mut configure, ctx)?;
+        run(&mut configure)?;
+
+        run(Command::new("cmake")
+            .arg("--build")
+            .arg(&dirs.build)
+            .args(["--parallel", &jobs(ctx)]))?;
+        run(Command::new("cmake").arg("--install").arg(&dirs.build))?;
+
+        dirs.probe(ctx)
+    }
+}
+
+#[cfg(feature = "autotools")]
+#[derive(Debug, Clone)]
+/// Builder for Autotools projects, see [autotools()]
+pub struct Autotools {
+    src_dir: PathBuf,
+    args: Vec<String>,
+}
+
+#[cfg(feature = "autotools")]
+impl Autotools {
+    /// Pass `arg` to the `configure` script, such as `--disable-docs`
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// Configure, build and install the project then return the library it provides
+    pub fn build(
+        &self,
+        ctx: &BuildInternalContext<'_>,
+    ) -> Result<Library, BuildInternalClosureError> {
+        let dirs = Dirs::new(ctx)?;
+        fs::create_dir_all(&dirs.build)
+            .map_err(|e| failed(&format!("failed to create {}: {}", dirs.build.display(), e)))?;
+
+        let mut configure = Command::new(absolute(&self.src_dir)?.join("configure"));
+        configure
+            .current_dir(&dirs.build)
+            .arg(format!("--prefix={}", dirs.prefix.display()))
+            .arg("--libdir=${prefix}/lib")
+            .arg("--enable-static")
+            .arg("--disable-shared");
+        if let (Some((triple, _)), Some(host)) = (cross_target(ctx), ctx.env.var("HOST")) {
+            configure
+                .arg(format!("--host={}", triple))
+                .arg(format!("--build={}", host));
+        }
+        if let Some(cc) = compiler(ctx) {
+            configure.arg(format!("CC={}", cc));
+        }
+        configure.args(&self.args);
+        set_pkg_config_path(&mut configure, ctx)?;
+        run(&mut configure)?;
+
+        run(Command::new("make")
+            .current_dir(&dirs.build)
+            .arg(format!("-j{}", jobs(ctx))))?;
+        run(Command::new("make").current_dir(&dirs.build).arg("install"))?;
+
+        dirs.probe(ctx)
+    }
+}
+
+// Directories used to build and install a library, in `OUT_DIR`
+struct Dirs {
+    root: PathBuf,
+    build: PathBuf,
+    prefix: PathBuf,
+}
+
+impl Dirs {
+    fn new(ctx: &BuildInternalContext<'_>) -> Result<Self, BuildInternalClosureError> {
+        let out_dir = ctx
+            .out_dir
+            .as_ref()
+            .ok_or_else(|| failed("OUT_DIR is not set"))?;
+        let root = absolute(out_dir)?.join("system-deps").join(&ctx.key);
+
+        fs::create_dir_all(&root)
+            .map_err(|e| failed(&format!("failed to create {}: {}", root.display(), e)))?;
+
+        Ok(Self {
+            build: root.join("build"),
+            prefix: root.join("install"),
+            root,
+        })
+    }
+
+    fn probe(&self, ctx: &BuildInternalContext<'_>) -> Result<Library, BuildInternalClosureError> {
+        let mut pkg_config = InternalPkgConfig::new()
+            .pc_dir(self.prefix.join("lib").join("pkgconfig"))
+            .pc_dir(self.prefix.join("share").join("pkgconfig"));
+        for dir in &ctx.pkg_config_path {
+            pkg_config = pkg_config.pc_dir(dir);
+        }
+        pkg_config.probe_with_env(&ctx.name, &ctx.min_version, ctx.env)
+    }
+}
+
+fn failed(details: &str) -> BuildInternalClosureError {
+    BuildInternalClosureError::failed(details)
+}
+
+fn absolute(path: &Path) -> Result<PathBuf, BuildInternalClosureError> {
+    let cwd = env::current_dir()
+        .map_err(|e| failed(&format!("failed to get the current directory: {}", e)))?;
+    Ok(cwd.join(path))
+}
+
+fn run(cmd: &mut Command) -> Result<(), BuildInternalClosureError> {
+    let status = cmd
+        .status()
+        .map_err(|e| failed(&format!("failed to run {:?}: {}", cmd, e)))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(failed(&format!("{:?} failed: {}", cmd, status)))
+    }
+}
+
+// Let the build find the libraries which have already been built internally
+fn set_pkg_config_path(
+    cmd: &mut Command,
+    ctx: &BuildInternalContext<'_>,
+) -> Result<(), BuildInternalClosureError> {
+    if ctx.pkg_config_path.is_empty() {
+        return Ok(());
+    }
+
+    let current = pc_files::var(ctx.env, "PKG_CONFIG_PATH").unwrap_or_default();
+    let paths = ctx
+        .pkg_config_path
+        .iter()
+        .cloned()
+        .chain(env::split_paths(&current));
+    let paths =
+        env::join_paths(paths).map_err(|e| failed(&format!("invalid PKG_CONFIG_PATH: {}", e)))?;
+    cmd.env("PKG_CONFIG_PATH", paths);
+    Ok(())
+}
+
+fn jobs(ctx: &BuildInternalContext<'_>) -> String {
+    ctx.env.var("NUM_JOBS").unwrap_or_else(|| "1".to_string())
+}
+
+// The C compiler to use, following the conventions of the `cc` crate
+fn compiler(ctx: &BuildInternalContext<'_>) -> Option<String> {
+    let target = ctx.target.as_deref().unwrap_or_default();
+    [
+        format!("CC_{}", target),
+        format!("CC_{}", target.replace('-', "_")),
+        "TARGET_CC".to_string(),
+        "CC".to_string(),
+    ]
+    .iter()
+    .find_map(|var| ctx.env.var(var))
+}
+
+// The C compiler to use when cross-compiling, defaulting to the GCC named after the target
+// without its vendor, like `aarch64-linux-gnu-gcc` for `aarch64-unknown-linux-gnu`
+fn cross_compiler(ctx: &BuildInternalContext<'_>) -> String {
+    compiler(ctx).unwrap_or_else(|| {
+        let target = ctx.target.as_deref().unwrap_or_default();
+        format!("{}-gcc", target.replace("-unknown-", "-"))
+    })
+}
+
+// The target to cross-compile for, if it is not the host, along with its description
+// if it is a builtin target rather than a custom target JSON
+fn cross_target<'a>(
+    ctx: &'a BuildInternalContext<'_>,
+) -> Option<(&'a str, Option<&'static TargetInfo>)> {
+    let target = ctx.target.as_deref()?;
+    if ctx.env.var("HOST").as_deref() == Some(target) {
+        return None;
+    }
+    Some((
+        target,
+        cfg_expr::targets::get_builtin_target_by_triple(target),
+    ))
+}
+
+#[cfg(feature = "meson")]
+// Meson `cpu_family` of `target`
+fn meson_cpu_family(target: &TargetInfo) -> &str {
+    match target.arch.as_str() {
+        "powerpc" => "ppc",
+        "powerpc64" => "ppc64",
+        "mips64" | "mips64r6" => "mips64",
+        "mips" | "mips32r6" => "mips",
+        arch => arch,
+    }
+}
+
+#[cfg(feature = "meson")]
+// Content of the Meson cross file describing `target`, `cc` being the compiler command
+// along with its arguments, such as `ccache gcc`
+pub(crate) fn meson_cross_file(target: &TargetInfo, cc: &str) -> String {
+    use cfg_expr::targets::Endian;
+
+    let system = match target.os.as_ref().map(|os| os.as_str()) {
+        Some("macos") => "darwin",
+        Some(os) => os,
+        None => "none",
+    };
+    let endian = match target.endian {
+        Endian::big => "big",
+        Endian::little => "little",
+    };
+
+    let cc = cc
+        .split_whitespace()
+        .map(|word| format!("'{}'", word.replace('\\', "\\\\").replace('\'', "\\'")))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "[binaries]\nc = [{}]\n\n[host_machine]\nsystem = '{}'\ncpu_family = '{}'\ncpu = '{}'\nendian = '{}'\n",
+        cc,
+        system,
+        meson_cpu_family(target),
+        target.arch.as_str(),
+        endian
+    )
+}
+
+#[cfg(feature = "cmake")]
+// CMake cache entries describing `target`
+pub(crate) fn cmake_cross_defines(target: &TargetInfo) -> Vec<String> {
+    let system = match target.os.as_ref().map(|os| os.as_str()) {
+        Some("linux") => "Linux",
+        Some("windows") => "Windows",
+        Some("macos") => "Darwin",
+        Some("ios") => "iOS",
+        Some("android") => "Android",
+        Some("freebsd") => "FreeBSD",
+        Some("netbsd") => "NetBSD",
+        Some("openbsd") => "OpenBSD",
+        Some(_) | None => "Generic",
+    };
+
+    vec![
+        format!("-DCMAKE_SYSTEM_NAME={}", system),
+        format!("-DCMAKE_SYSTEM_PROCESSOR={}", target.arch.as_str()),
+    ]
+}
@@ -0,0 +1,369 @@
+// On-disk cache of the libraries found by the probe backend, shared by all the build
+// scripts using the same target directory.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::UNIX_EPOCH,
+};
+
+use toml::{map::Map, Value};
+
+use crate::{
+    lock::{fnv1a, FNV_OFFSET_BASIS},
+    pc_files, EnvSource, Error, InternalLib, Library, PkgConfigBackend, ProbeBackend, Source,
+};
+
+const CACHE_DIR: &str = "system-deps-probe-cache";
+
+pub(crate) struct ProbeCache {
+    dir: PathBuf,
+    // Hash of the inputs shared by all the probes: target, pkg-config environment and search paths
+    inputs: u64,
+}
+
+impl ProbeCache {
+    // The cache is located in the directory of the build profile, which contains
+    // the `build` directory where `OUT_DIR` is created.
+    pub(crate) fn new(out_dir: &Path, env: &dyn EnvSource) -> Option<Self> {
+        let profile_dir = out_dir
+            .ancestors()
+            .find(|dir| dir.file_name() == Some("build".as_ref()))?
+            .parent()?;
+
+        let mut names = pc_files::env_variables(env);
+        for base in [
+            "PKG_CONFIG_ALLOW_CROSS",
+            "PKG_CONFIG_ALL_STATIC",
+            "PKG_CONFIG_ALL_DYNAMIC",
+        ] {
+            names.extend(pc_files::variable_names(env, base));
+        }
+        names.push("SYSROOT".to_string());
+
+        let target = env.var("TARGET").unwrap_or_default();
+        let mut inputs = fnv1a(FNV_OFFSET_BASIS, target.as_bytes());
+        for name in &names {
+            inputs = fnv1a(inputs, name.as_bytes());
+            inputs = fnv1a(inputs, b"=");
+            inputs = fnv1a(inputs, env.var(name).unwrap_or_default().as_bytes());
+            inputs = fnv1a(inputs, b"\n");
+        }
+
+        // Adding or removing a .pc file changes the modification time of its directory.
+        // pkg-config looks into its built-in search path unless `PKG_CONFIG_LIBDIR` is defined.
+        let mut dirs: Vec<_> =
+            env::split_paths(&pc_files::var(env, "PKG_CONFIG_PATH").unwrap_or_default()).collect();
+        match pc_files::var(env, "PKG_CONFIG_LIBDIR") {
+            Some(libdir) => dirs.extend(env::split_paths(&libdir)),
+            None => dirs.extend(pc_files::default_search_path(&pc_files::executable(env))),
+        }
+        for dir in dirs {
+            inputs = fnv1a(inputs, dir.to_string_lossy().as_bytes());
+            inputs = fnv1a(inputs, b"=");
+            inputs = fnv1a(inputs, mtime(&dir).unwrap_or_default().as_bytes());
+            inputs = fnv1a(inputs, b"\n");
+        }
+
+        Some(Self {
+            dir: profile_dir.join(CACHE_DIR),
+            inputs,
+        })
+    }
+
+    fn path(&self, name: &str, version: &str, statik: bool) -> PathBuf {
+        let mut hash = fnv1a(self.inputs, name.as_bytes());
+        hash = fnv1a(hash, b"\n");
+        hash = fnv1a(hash, version.as_bytes());
+        hash = fnv1a(hash, if statik { b"static" } else { b"dynamic" });
+        self.dir.join(format!("{:016x}.toml", hash))
+    }
+
+    // Return the cached library, if any and if none of its .pc files have been modified since
+    fn load(&self, name: &str, version: &str, statik: bool) -> Option<Library> {
+        let content = fs::read_to_string(self.path(name, version, statik)).ok()?;
+        let table = content.parse::<toml::Table>().ok()?;
+
+        let pc_files = table.get("pc-files")?.as_array()?;
+        // Entries without .pc files could never be invalidated
+        if pc_files.is_empty() {
+            return None;
+        }
+        for pc_file in pc_files {
+            let pc_file = pc_file.as_table()?;
+            let path = pc_file.get("path")?.as_str()?;
+            if mtime(Path::new(path))? != pc_file.get("mtime")?.as_str()? {
+                return None;
+            }
+        }
+
+        from_table(&table)
+    }
+
+    fn store(&self, name: &str, version: &str, statik: bool, lib: &Library) {
+        // Libraries whose .pc files could not be located are not cached, as the entry
+        // would not be invalidated when they are modified
+        if lib.pc_files.is_empty() {
+            return;
+        }
+        let table = match to_table(lib) {
+            Some(table) => table,
+            None => return,
+        };
+        let path = self.path(name, version, statik);
+
+        // Write to a temporary file then rename it so concurrent build scripts never
+        // read a partially written entry. Errors are ignored as the cache is only an optimization.
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let tmp = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        if fs::create_dir_all(&self.dir).is_ok()
+            && fs::write(&tmp, table.to_string()).is_ok()
+            && fs::rename(&tmp, &path).is_err()
+        {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+}
+
+// Backend looking for the libraries in the cache before running pkg-config.
+// Other backends are not cached as the entries do not depend on them.
+pub(crate) struct CachedBackend<'a> {
+    pub(crate) cache: &'a ProbeCache,
+}
+
+impl ProbeBackend for CachedBackend<'_> {
+    fn probe(
+        &self,
+        name: &str,
+        version: &str,
+        statik: bool,
+        env: &dyn EnvSource,
+    ) -> Result<Library, Error> {
+        if let Some(lib) = self.cache.load(name, version, statik) {
+            return Ok(lib);
+        }
+
+        let lib = PkgConfigBackend.probe(name, version, statik, env)?;
+        self.cache.store(name, version, statik, &lib);
+        Ok(lib)
+    }
+}
+
+fn mtime(path: &Path) -> Option<String> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some(since_epoch.as_nanos().to_string())
+}
+
+fn strings(values: &[String]) -> Value {
+    Value::Array(values.iter().cloned().map(Value::String).collect())
+}
+
+fn paths(values: &[PathBuf]) -> Option<Value> {
+    let values = values
+        .iter()
+        .map(|p| p.to_str().map(|s| Value::String(s.to_string())))
+        .collect::<Option<Vec<_>>>()?;
+    Some(Value::Array(values))
+}
+
+fn to_table(lib: &Library) -> Option<Map<String, Value>> {
+    let mut table = Map::new();
+    table.insert("name".into(), Value::String(lib.name.clone()));
+    table.insert("version".into(), Value::String(lib.version.clone()));
+    table.insert("statik".into(), Value::Boolean(lib.statik));
+
+    let libs = lib
+        .libs
+        .iter()
+        .map(|l| {
+            let mut t = Map::new();
+            t.insert("name".into(), Value::String(l.name.clone()));
+            t.insert(
+                "is-static-available".into(),
+                Value::Boolean(l.is_static_available),
+            );
+            Value::Table(t)
+        })
+        .collect();
+    table.insert("libs".into(), Value::Array(libs));
+
+    table.insert("link-paths".into(), paths(&lib.link_paths)?);
+    table.insert("frameworks".into(), strings(&lib.frameworks));
+    table.insert("framework-paths".into(), paths(&lib.framework_paths)?);
+    table.insert("include-paths".into(), paths(&lib.include_paths)?);
+    table.insert(
+        "ld-args".into(),
+        Value::Array(lib.ld_args.iter().map(|args| strings(args)).collect()),
+    );
+
+    // Defines without value are stored separately as TOML has no null value
+    let mut defines = lib.defines.iter().collect::<Vec<_>>();
+    defines.sort();
+    let mut with_value = Map::new();
+    let mut without_value = Vec::new();
+    for (name, value) in defines {
+        match value {
+            Some(value) => {
+                with_value.insert(name.clone(), Value::String(value.clone()));
+            }
+            None => without_value.push(name.clone()),
+        }
+    }
+    table.insert("defines".into(), Value::Table(with_value));
+    table.insert("defines-without-value".into(), strings(&without_value));
+
+    let pc_files = lib
+        .pc_files
+        .iter()
+        .map(|path| {
+            let mut t = Map::new();
+            t.insert("path".into(), Value::String(path.to_str()?.to_string()));
+            t.insert("mtime".into(), Value::String(mtime(path)?));
+            Some(Value::Table(t))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    table.insert("pc-files".into(), Value::Array(pc_files));
+
+    Some(table)
+}
+
+fn from_table(table: &Map<String, Value>) -> Option<Library> {
+    let string = |key: &str| table.get(key)?.as_str().map(|s| s.to_string());
+    let string_list = |value: &Value| -> Option<Vec<String>> {
+        value
+            .as_array()?
+            .iter()
+            .map(|v| v.as_str().map(|s| s.to_string()))
+            .collect()
+    };
+    let strings = |key: &str| string_list(table.get(key)?);
+    let paths = |key: &str| Some(strings(key)?.into_iter().map(PathBuf::from).collect());
+
+    let name = string("name")?;
+    let mut lib = Library::from_env_variables(&name);
+    lib.source = Source::PkgConfig;
+    lib.version = string("version")?;
+    lib.statik = table.get("statik")?.as_bool()?;
+    lib.libs = table
+        .get("libs")?
+        .as_array()?
+        .iter()
+        .map(|l| {
+            let l = l.as_table()?;
+            Some(InternalLib::new(
+                l.get("name")?.as_str()?.to_string(),
+                l.get("is-static-available")?.as_bool()?,
+            ))
+        })
+        .collect::<Option<_>>()?;
+    lib.link_paths = paths("link-paths")?;
+    lib.frameworks = strings("frameworks")?;
+    lib.framework_paths = paths("framework-paths")?;
+    lib.include_paths = paths("include-paths")?;
+    lib.ld_args = table
+        .get("ld-args")?
+        .as_array()?
+        .iter()
+        .map(string_list)
+        .collect::<Option<_>>()?;
+
+    let mut defines = HashMap::new();
+    for (name, value) in table.get("defines")?.as_table()? {
+        defines.insert(name.clone(), Some(value.as_str()?.to_string()));
+    }
+    for name in strings("defines-without-value")? {
+        defines.insert(name, None);
+    }
+    lib.defines = defines;
+
+    lib.pc_files = table
+        .get("pc-files")?
+        .as_array()?
+        .iter()
+        .map(|pc| Some(PathBuf::from(pc.as_table()?.get("path")?.as_str()?)))
+        .collect::<Option<_>>()?;
+
+    Some(lib)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_and_invalidation() {
+        let root = env::temp_dir().join(format!("system-deps-cache-{}", std::process::id()));
+        let out_dir = root
+            .join("debug")
+            .join("build")
+            .join("foo-sys-1234")
+            .join("out");
+        fs::create_dir_all(&out_dir).unwrap();
+        let pc_file = root.join("testlib.pc");
+        fs::write(&pc_file, "Version: 1.2.3\n").unwrap();
+
+        let mut lib = Library::from_env_variables("testlib");
+        lib.source = Source::PkgConfig;
+        lib.version = "1.2.3".into();
+        lib.libs = vec![InternalLib::new("test".into(), false)];
+        lib.link_paths = vec!["/usr/lib".into()];
+        lib.include_paths = vec!["/usr/include/testlib".into()];
+        lib.ld_args = vec![vec!["-Wl,-z".into(), "now".into()]];
+        lib.defines.insert("BADGER".into(), Some("yes".into()));
+        lib.defines.insert("AWESOME".into(), None);
+        lib.pc_files = vec![pc_file.clone()];
+
+        let mut env = HashMap::new();
+        env.insert("TARGET", "x86_64-unknown-linux-gnu".to_string());
+        let cache = ProbeCache::new(&out_dir, &env).unwrap();
+        assert_eq!(cache.dir, root.join("debug").join(CACHE_DIR));
+        assert!(cache.load("testlib", "1", false).is_none());
+
+        cache.store("testlib", "1", false, &lib);
+        let cached = cache.load("testlib", "1", false).unwrap();
+        assert_eq!(cached.name, "testlib");
+        assert_eq!(cached.version, "1.2.3");
+        assert_eq!(cached.libs, lib.libs);
+        assert_eq!(cached.link_paths, lib.link_paths);
+        assert_eq!(cached.include_paths, lib.include_paths);
+        assert_eq!(cached.ld_args, lib.ld_args);
+        assert_eq!(cached.defines, lib.defines);
+        assert_eq!(cached.pc_files, lib.pc_files);
+
+        // Other versions, link kinds or targets are different entries
+        assert!(cache.load("testlib", "2", false).is_none());
+        assert!(cache.load("testlib", "1", true).is_none());
+        env.insert("TARGET", "aarch64-unknown-linux-gnu".to_string());
+        let other = ProbeCache::new(&out_dir, &env).unwrap();
+        assert!(other.load("testlib", "1", false).is_none());
+        // as are other pkg-config environments, including the target-specific variables
+        env.insert("HOST", "x86_64-unknown-linux-gnu".to_string());
+        let with_host = ProbeCache::new(&out_dir, &env).unwrap();
+        env.insert("TARGET_PKG_CONFIG_PATH", "/target".to_string());
+        let other = ProbeCache::new(&out_dir, &env).unwrap();
+        assert_ne!(other.inputs, with_host.inputs);
+
+        // Modifying the .pc file invalidates the entry
+        fs::File::options()
+            .write(true)
+            .open(&pc_file)
+            .unwrap()
+            .set_modified(UNIX_EPOCH)
+            .unwrap();
+        assert!(cache.load("testlib", "1", false).is_none());
+
+        // Libraries without .pc files are never cached
+        lib.pc_files.clear();
+        cache.store("testlib", "3", false, &lib);
+        assert!(!cache.path("testlib", "3", false).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -70,6 +70,20 @@
 //! println!("found test-data");
 //! ```
 //!
+//! Custom cfg can also be exported when a dependency is found using `cfgs-if-found`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! libva = { version = "1.0", optional = true, cfgs-if-found = ["have_vaapi"] }
+//! ```
+//!
+//! The version of each dependency found is available to the crate through the
+//! `SYSTEM_DEPS_$DEP_VERSION` environment variable, with `$DEP` being the upper case name of the key:
+//!
+//! ```ignore
+//! const LIBVA_VERSION: &str = env!("SYSTEM_DEPS_LIBVA_VERSION");
+//! ```
+//!
 //! # Overriding library name
 //! `toml` keys cannot contain dot characters so if your library name does, you can define it using the `name` field:
 //!
@@ -98,6 +112,48 @@
 //! v2 = { version = "2.0", fallback-names = ["libfoo2"] }
 //! ```
 //!
+//! # Alternative libraries
+//!
+//! A dependency can be fulfilled by one library among several alternatives, each of them
+//! with its own name, version and, optionally, the feature enabling it:
+//!
+//! ```toml
+//! [package.metadata.system-deps.ssl]
+//! any-of = [
+//!     { name = "openssl", version = "1.1" },
+//!     { name = "libressl", version = "3.0", feature = "libressl" },
+//! ]
+//! ```
+//!
+//! The first alternative which is found is used and `system-deps` sets the cfg
+//! `system_deps_$DEP_is_$ALT`, for example `system_deps_ssl_is_libressl`, so code can
+//! check which one has been selected using `#[cfg(system_deps_ssl_is_libressl)]`.
+//!
+//! The names of the libraries are defined by the alternatives, so `name` and `fallback-names`
+//! cannot be used in the same table as `any-of`.
+//! Unless the dependency is `optional`, at least one of the alternatives has to be enabled.
+//!
+//! # Workspace dependencies
+//!
+//! Crates of a workspace can share the definition of their dependencies by declaring them in the
+//! `[workspace.metadata.system-deps]` section of the workspace root manifest:
+//!
+//! ```toml
+//! [workspace.metadata.system-deps]
+//! glib = { name = "glib-2.0", version = "2.64" }
+//! ```
+//!
+//! and inheriting them using `workspace = true`. Other settings, such as `feature` or `optional`, can be
+//! added by each crate:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! glib = { workspace = true, optional = true }
+//! ```
+//!
+//! The manifest and the name of the metadata table can also be changed using
+//! [Config::manifest_path] and [Config::metadata_table].
+//!
 //! # Feature versions
 //!
 //! `-sys` crates willing to support various versions of their underlying system libraries
@@ -128,6 +184,27 @@
 //! v1_18 = { version = "1.18", name = "gstreamer-gl-egl-1.0" }
 //! ```
 //!
+//! Features with other names can be used through the `overrides` table, keyed by feature name
+//! and accepting the same `version`, `name`, `fallback-names` and `optional` settings:
+//!
+//! ```toml
+//! [package.metadata.system-deps.gstreamer_1_0]
+//! name = "gstreamer-1.0"
+//! version = "1.0"
+//! overrides = { gst-next = { version = "1.24" }, "player" = { version = "1.20" } }
+//! ```
+//!
+//! When several overrides are enabled, the library must match the version ranges of all of them,
+//! such as `>= 1.4, < 2` for `{ version = ">= 1.2, < 2" }` and `{ version = "1.4" }`,
+//! and the other settings are taken from the one requiring the highest version.
+//! If some require the same version, `overrides` entries take precedence over `v*` keys,
+//! then the entry whose feature name comes last alphabetically is picked.
+//! The resolved range is reported in [Library::required_version].
+//!
+//! Declarations which are likely to be mistakes, such as a feature version override for a feature
+//! missing from `[features]` or `fallback-names` repeating `name`, are reported as `cargo:warning`
+//! by [Config::probe]. They can also be retrieved using [Config::check_metadata].
+//!
 //! # Target specific dependencies
 //!
 //! You can define target specific dependencies:
@@ -152,6 +229,40 @@
 //! - `target_pointer_width`
 //! - `target_vendor`
 //! - `unix` and `windows`
+//! - `feature`
+//! - `target_feature`, `debug_assertions` and custom cfg defined using `--cfg` in `RUSTFLAGS`,
+//!   which are retrieved from the `CARGO_CFG_*` environment variables set by cargo
+//!
+//! `feature` can be combined with the target keys to express more complex conditions than
+//! the `feature` setting of a dependency:
+//!
+//! ```toml
+//! [package.metadata.system-deps.'cfg(all(feature = "x11", target_os = "linux"))']
+//! x11 = "1.6"
+//! ```
+//!
+//! For example, a dependency only required when linking the C runtime statically can be declared using:
+//!
+//! ```toml
+//! [package.metadata.system-deps.'cfg(target_feature = "crt-static")']
+//! libunwind = "1"
+//! ```
+//!
+//! `cfg()` tables can be nested, in which case all their expressions have to match:
+//!
+//! ```toml
+//! [package.metadata.system-deps.'cfg(unix)'.'cfg(target_pointer_width = "64")']
+//! testlib = "1"
+//! ```
+//!
+//! The settings of a dependency can also be changed for some targets only, the same way as
+//! [feature versions](#feature-versions) do:
+//!
+//! ```toml
+//! [package.metadata.system-deps.gtk4]
+//! version = "4.0"
+//! 'cfg(target_os = "macos")' = { name = "gtk4-macos", version = "4.6" }
+//! ```
 //!
 //! # Overriding build flags
 //!
@@ -187,6 +298,29 @@
 //! }
 //! ```
 //!
+//! Closures registered with [Config::add_build_internal_with] receive a [BuildInternalContext] instead,
+//! providing the target, `OUT_DIR`, the parsed version range and the dependencies already resolved.
+//!
+//! If a library needs another dependency to be built first, list its key in `requires`:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", requires = ["testdata"] }
+//! testdata = "4.5"
+//! ```
+//!
+//! Dependencies are resolved, and built internally if needed, after the ones they require.
+//! The directories containing the `.pc` files of the libraries already built internally are
+//! passed to the next closures in [BuildInternalContext::pkg_config_path], and added to the
+//! search path of `pkg-config` when looking for the dependencies requiring them.
+//!
+//! The `meson`, `cmake` and `autotools` cargo features provide builders in the `build_internal` module
+//! which build a library from its local sources and return it, for example
+//! `system_deps::build_internal::meson("vendor/testlib").build(ctx)`.
+//!
+//! [InternalPkgConfig] can be used instead of [Library::from_internal_pkg_config] to search several
+//! directories or to relocate a library which has been installed in a staging directory.
+//!
 //! This feature can be controlled using the `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` environment variable
 //! which can have the following values:
 //!
@@ -202,13 +336,125 @@
 //! By default all libraries are dynamically linked, except when build internally as [described above](#internally-build-system-libraries).
 //! Libraries can be statically linked by defining the environment variable `SYSTEM_DEPS_$NAME_LINK=static`.
 //! You can also use `SYSTEM_DEPS_LINK=static` to statically link all the libraries.
+//!
+//! # Rebuild tracking
+//!
+//! `system-deps` asks cargo to re-run the build script when one of the `.pc` files used to resolve
+//! the dependencies, including the ones of their transitive requirements, is modified.
+//! It is also re-run if the `PKG_CONFIG`, `PKG_CONFIG_PATH`, `PKG_CONFIG_LIBDIR` or `PKG_CONFIG_SYSROOT_DIR`
+//! environment variables are changed, including their target-specific variants such as
+//! `PKG_CONFIG_PATH_$TARGET` or `TARGET_PKG_CONFIG_PATH` when cross-compiling.
+//!
+//! The directories where libraries are searched can be tracked as well using
+//! [Config::rerun_if_link_paths_changed]:
+//!
+//! ```should_panic
+//! fn main() {
+//!     system_deps::Config::new()
+//!         .rerun_if_link_paths_changed(true)
+//!         .probe()
+//!         .unwrap();
+//! }
+//! ```
+//!
+//! # Inspecting the dependencies
+//!
+//! [Config::probe] prints the cargo directives right away. Build scripts willing to inspect or change them
+//! can use [Config::resolve] instead, which has no side effect, and emit the directives themselves:
+//!
+//! ```should_panic
+//! fn main() {
+//!     let deps = system_deps::Config::new().resolve().unwrap();
+//!
+//!     let mut flags = deps.build_flags().unwrap();
+//!     // Do not export the headers paths to dependent crates
+//!     flags.retain(|f| !matches!(f, system_deps::BuildFlag::Include(_)));
+//!     print!("{}", flags);
+//! }
+//! ```
+//!
+//! # Declaring dependencies from the build script
+//!
+//! Dependencies can also be declared using [Config::add_dependency], for example if they are only known
+//! when the build script is running. They are handled exactly as the ones declared in `Cargo.toml`,
+//! which they replace if they have the same name:
+//!
+//! ```should_panic
+//! fn main() {
+//!     system_deps::Config::new()
+//!         .add_dependency(
+//!             "glib",
+//!             system_deps::DependencySpec {
+//!                 name: Some("glib-2.0".into()),
+//!                 version: Some("2.64".into()),
+//!                 ..Default::default()
+//!             },
+//!         )
+//!         .probe()
+//!         .unwrap();
+//! }
+//! ```
+//!
+//! # Testing build scripts
+//!
+//! The environment and the way libraries are looked for can be replaced using
+//! [Config::env_source] and [Config::probe_backend], so build scripts can be tested
+//! without having the libraries installed. [FakeBackend] returns the libraries it has been given:
+//!
+//! ```
+//! use std::collections::HashMap;
+//!
+//! let mut env = HashMap::new();
+//! env.insert("CARGO_MANIFEST_DIR", "/path/to/my-crate".to_string());
+//!
+//! let config = system_deps::Config::new()
+//!     .env_source(env)
+//!     .probe_backend(system_deps::FakeBackend::new().library("testlib", "1.2.3"));
+//! ```
+//!
+//! # Probe cache
+//!
+//! In large workspaces the same libraries are looked for by many build scripts.
+//! Defining `SYSTEM_DEPS_PROBE_CACHE=1` lets them share the libraries found using an on-disk cache
+//! located in the target directory, next to the `build` directory.
+//!
+//! Only the libraries found by `pkg-config` are cached, not the ones returned by a custom [ProbeBackend].
+//! Entries are specific to the library name, the version requirement, the link kind and the target.
+//! They are invalidated when the `PKG_CONFIG_*` environment variables or their target-specific
+//! variants, such as `PKG_CONFIG_PATH_$TARGET` or `TARGET_PKG_CONFIG_PATH`, change, when the content
+//! of the directories listed in `PKG_CONFIG_PATH` or `PKG_CONFIG_LIBDIR`, or of the default search path
+//! of pkg-config, changes, or when one of the `.pc` files the library has been resolved from is modified.
+//! Libraries whose `.pc` files cannot be located are not cached.
+//!
+//! # Lock file
+//!
+//! The resolved dependencies can be recorded in a `system-deps.lock` file, located next to `Cargo.lock`,
+//! to make sure builds keep using the same system libraries.
+//! For each dependency, it records the name of the module which has been found, its version
+//! and a hash of the content of its `.pc` files.
+//!
+//! - `SYSTEM_DEPS_UPDATE_LOCK=1` writes or updates the lock file with the dependencies of the crate being built;
+//! - `SYSTEM_DEPS_LOCKED=1` makes [Config::probe] fail if the dependencies found on the system do not match the lock file.
+//!
+//! Both variables accept `1`, `true`, `yes` or `on` to enable them and `0`, `false`, `no` or `off` to disable them.
+//! The lock file is locked while being updated so crates of the same workspace can be built in parallel,
+//! by creating a `system-deps.lock.lck` file next to it which is removed once done.
+//!
+//! # Software Bill of Materials
+//!
+//! When the `sbom` feature is enabled, [Config::probe] writes a Software Bill of Materials listing
+//! the system libraries linked into the crate to `OUT_DIR`, both as
+//! [SPDX 2.3](https://spdx.github.io/spdx-spec/v2.3/) (`system-deps.spdx.json`) and
+//! [CycloneDX 1.5](https://cyclonedx.org/docs/1.5/json/) (`system-deps.cdx.json`) JSON documents.
+//! They contain the name and version of each library, if it is statically or dynamically linked
+//! and the `.pc` file it has been resolved from.
+//!
+//! The documents are reproducible: libraries are sorted as in [Dependencies::iter] and
+//! no timestamp is recorded.
+//! They can also be generated using [Dependencies::sbom].
 
 #![deny(missing_docs)]
 
-#[cfg(test)]
-#[macro_use]
-extern crate lazy_static;
-
 #[cfg(test)]
 mod test;
 
@@ -220,20 +466,85 @@ use std::ops::RangeBounds;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+#[cfg(any(feature = "meson", feature = "cmake", feature = "autotools"))]
+pub mod build_internal;
+
+mod backend;
+pub use backend::{EnvSource, FakeBackend, PkgConfigBackend, ProbeBackend, ProcessEnv};
+
+mod cache;
+mod lock;
+use lock::Lockfile;
+
 mod metadata;
 use metadata::MetaData;
 
+mod pc_files;
+pub use metadata::{MetadataError, MetadataLocation, MetadataWarning};
+
+#[cfg(feature = "sbom")]
+mod sbom;
+#[cfg(feature = "sbom")]
+pub use sbom::SbomFormat;
+
 /// system-deps errors
+///
+/// The errors about a dependency carry its `toml` key, so build scripts can react to them:
+///
+/// ```no_run
+/// match system_deps::Config::new().probe() {
+///     Ok(deps) => {}
+///     // Only use the vendored copy if the library is not installed at all
+///     Err(system_deps::Error::PkgConfig {
+///         key: Some(key),
+///         kind: system_deps::ProbeErrorKind::NotFound,
+///         ..
+///     }) if key == "testlib" => {
+///         // build the vendored library
+///     }
+///     Err(e) => panic!("{}", e),
+/// }
+/// ```
 #[derive(Debug)]
 pub enum Error {
-    /// pkg-config error
-    PkgConfig(pkg_config::Error),
+    /// pkg-config did not find a library matching the requested version
+    PkgConfig {
+        /// The `toml` key of the dependency, `None` if the error has not been raised by [Config]
+        key: Option<String>,
+        /// The name of the library which has been looked for
+        name: String,
+        /// The requested version range
+        version: String,
+        /// Whether the library is missing or has the wrong version
+        kind: ProbeErrorKind,
+        /// The pkg-config error
+        cause: pkg_config::Error,
+    },
     /// One of the `Config::add_build_internal` closures failed
-    BuildInternalClosureError(String, BuildInternalClosureError),
+    BuildInternalClosureError {
+        /// The name of the library which was being built
+        name: String,
+        /// The error returned by the closure
+        error: BuildInternalClosureError,
+    },
     /// Failed to read `Cargo.toml`
     FailToRead(String, std::io::Error),
     /// Raised when an error is detected in the metadata defined in `Cargo.toml`
-    InvalidMetadata(String),
+    InvalidMetadata {
+        /// The `toml` key of the dependency the error is about, if any
+        key: Option<String>,
+        /// Description of the error
+        message: String,
+    },
+    /// The metadata of a manifest cannot be parsed
+    InvalidManifest {
+        /// The path of the manifest
+        path: PathBuf,
+        /// The parsing error
+        error: MetadataError,
+        /// The location of the error in the manifest, if it could be found
+        location: Option<MetadataLocation>,
+    },
     /// Raised when dependency defined manually using `SYSTEM_DEPS_$NAME_NO_PKG_CONFIG`
     /// did not define at least one lib using `SYSTEM_DEPS_$NAME_LIB` or
     /// `SYSTEM_DEPS_$NAME_LIB_FRAMEWORK`
@@ -245,26 +556,124 @@ pub enum Error {
     /// `SYSTEM_DEPS_$NAME_BUILD_INTERNAL=always' or `SYSTEM_DEPS_$NAME_BUILD_INTERNAL=auto',
     /// but not closure has been defined using `Config::add_build_internal` to build
     /// this lib
-    BuildInternalNoClosure(String, String),
+    BuildInternalNoClosure {
+        /// The `toml` key of the dependency
+        key: String,
+        /// The name of the library to build
+        name: String,
+        /// The requested version range
+        version: String,
+    },
     /// The library which has been build internally does not match the
     /// required version defined in `Cargo.toml`
-    BuildInternalWrongVersion(String, String, String),
+    BuildInternalWrongVersion {
+        /// The `toml` key of the dependency
+        key: String,
+        /// The name of the library which has been built
+        name: String,
+        /// The version of the library which has been built
+        version: String,
+        /// The requested version range
+        required_version: String,
+    },
     /// The `cfg()` expression used in `Cargo.toml` is currently not supported
     UnsupportedCfg(String),
+    /// Failed to write a file
+    FailToWrite(String, std::io::Error),
+    /// The `system-deps.lock` file is invalid or does not contain the crate
+    InvalidLockfile(String),
+    /// `SYSTEM_DEPS_LOCKED` is enabled and the resolved dependencies do not
+    /// match the ones recorded in `system-deps.lock`
+    LockfileMismatch(String),
+    /// An environment variable required by `system-deps`, usually defined by cargo, is not set
+    MissingEnvVariable(String),
+    /// An environment variable has a value which cannot be parsed
+    InvalidEnvVariable {
+        /// The name of the variable
+        name: String,
+        /// Its value
+        value: String,
+    },
+    /// Several required dependencies have not been found.
+    /// If only one of them is missing, [Error::PkgConfig] is raised instead.
+    MultipleMissing(Vec<MissingDependency>),
+    /// A [ProbeBackend] other than [PkgConfigBackend] did not find a library
+    /// matching the requested version
+    ProbeFailure {
+        /// The `toml` key of the dependency, `None` if the error has not been raised by [Config]
+        key: Option<String>,
+        /// The name of the library which has been looked for
+        name: String,
+        /// The requested version range
+        version: String,
+        /// Whether the library is missing or has the wrong version
+        kind: ProbeErrorKind,
+        /// Description of the failure
+        cause: String,
+    },
 }
 
-impl From<pkg_config::Error> for Error {
-    fn from(err: pkg_config::Error) -> Self {
-        Self::PkgConfig(err)
+/// Why a library has not been found by a [ProbeBackend],
+/// reported by [Error::PkgConfig] and [Error::ProbeFailure]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeErrorKind {
+    /// The library is not installed
+    NotFound,
+    /// The library is installed but its version does not match the requested range
+    WrongVersion,
+    /// Another failure, such as pkg-config not being available
+    Other,
+}
+
+impl Error {
+    // Error of pkg-config looking for `name`, `pkg_config` being used to tell whether the
+    // library is missing or has the wrong version
+    pub(crate) fn pkg_config(
+        name: &str,
+        version: &str,
+        cause: pkg_config::Error,
+        pkg_config: &InternalPkgConfig,
+        env: &dyn EnvSource,
+    ) -> Self {
+        let kind = match &cause {
+            pkg_config::Error::ProbeFailure { .. } | pkg_config::Error::Failure { .. } => {
+                pkg_config.failure_kind(name, env)
+            }
+            _ => ProbeErrorKind::Other,
+        };
+        Self::PkgConfig {
+            key: None,
+            name: name.to_string(),
+            version: version.to_string(),
+            kind,
+            cause,
+        }
+    }
+
+    pub(crate) fn invalid_metadata(key: Option<&str>, message: impl Into<String>) -> Self {
+        Self::InvalidMetadata {
+            key: key.map(|k| k.to_string()),
+            message: message.into(),
+        }
+    }
+
+    // Set the key of the dependency the backend was looking for
+    fn with_key(mut self, dep_key: &str) -> Self {
+        if let Self::PkgConfig { key, .. } | Self::ProbeFailure { key, .. } = &mut self {
+            key.get_or_insert_with(|| dep_key.to_string());
+        }
+        self
     }
 }
 
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Self::PkgConfig(e) => Some(e),
-            Self::BuildInternalClosureError(_, e) => Some(e),
+            Self::PkgConfig { cause, .. } => Some(cause),
+            Self::BuildInternalClosureError { error, .. } => Some(error),
+            Self::InvalidManifest { error, .. } => Some(error),
             Self::FailToRead(_, e) => Some(e),
+            Self::FailToWrite(_, e) => Some(e),
             _ => None,
         }
     }
@@ -273,10 +682,34 @@ impl std::error::Error for Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::PkgConfig(e) => write!(f, "{}", e),
-            Self::BuildInternalClosureError(s, e) => write!(f, "Failed to build {}: {}", s, e),
+            Self::PkgConfig { cause, .. } => write!(f, "{}", cause),
+            Self::BuildInternalClosureError { name, error } => {
+                write!(f, "Failed to build {}: {}", name, error)
+            }
             Self::FailToRead(s, _) => write!(f, "{}", s),
-            Self::InvalidMetadata(s) => write!(f, "{}", s),
+            Self::InvalidMetadata {
+                key: Some(key),
+                message,
+            } => write!(f, "{}: {}", key, message),
+            Self::InvalidMetadata { key: None, message } => write!(f, "{}", message),
+            Self::InvalidManifest {
+                path,
+                error,
+                location: Some(location),
+            } => write!(
+                f,
+                "{}:{}:{}: {}\n{}",
+                path.display(),
+                location.line,
+                location.column,
+                error,
+                location.snippet
+            ),
+            Self::InvalidManifest {
+                path,
+                error,
+                location: None,
+            } => write!(f, "{}: {}", path.display(), error),
             Self::MissingLib(s) => write!(
                 f,
                 "You should define at least one lib using {} or {}",
@@ -284,25 +717,101 @@ impl fmt::Display for Error {
                 EnvVariable::new_lib_framework(s),
             ),
             Self::BuildInternalInvalid(s) => write!(f, "{}", s),
-            Self::BuildInternalNoClosure(s1, s2) => write!(
+            Self::BuildInternalNoClosure { key, version, .. } => write!(
                 f,
                 "Missing build internal closure for {} (version {})",
-                s1, s2
+                key, version
             ),
-            Self::BuildInternalWrongVersion(s1, s2, s3) => write!(
+            Self::BuildInternalWrongVersion {
+                name,
+                version,
+                required_version,
+                ..
+            } => write!(
                 f,
-                "Internally built {} {} but minimum required version is {}",
-                s1, s2, s3
+                "Internally built {} {} but required version is {}",
+                name, version, required_version
             ),
             Self::UnsupportedCfg(s) => write!(f, "Unsupported cfg() expression: {}", s),
+            Self::FailToWrite(s, _) => write!(f, "{}", s),
+            Self::InvalidLockfile(s) => write!(f, "Invalid lock file: {}", s),
+            Self::LockfileMismatch(s) => {
+                write!(f, "Dependencies do not match the lock file: {}", s)
+            }
+            Self::ProbeFailure { cause, .. } => write!(f, "{}", cause),
+            Self::MissingEnvVariable(s) => write!(f, "${} not set", s),
+            Self::InvalidEnvVariable { name, value } => {
+                write!(f, "Invalid value for {}: {}", name, value)
+            }
+            Self::MultipleMissing(missing) => {
+                writeln!(
+                    f,
+                    "{} system dependencies have not been found:",
+                    missing.len()
+                )?;
+                for m in missing {
+                    writeln!(f, "- {}", m)?;
+                }
+                match missing.first().and_then(|m| m.pkg_config_path.as_ref()) {
+                    Some(path) => write!(f, "PKG_CONFIG_PATH: {}", path),
+                    None => write!(f, "PKG_CONFIG_PATH is not set"),
+                }
+            }
         }
     }
 }
 
+#[derive(Debug)]
+/// A required system dependency which has not been found, reported by [Error::MultipleMissing].
+pub struct MissingDependency {
+    /// The name of the `toml` key defining the dependency in `Cargo.toml`
+    pub key: String,
+    /// The names which have been looked for, including the fallback names,
+    /// along with the version range required for each of them
+    pub names: Vec<(String, String)>,
+    /// The enabled feature which required the dependency or its version, if any
+    pub feature: Option<String>,
+    /// The value of `PKG_CONFIG_PATH` when the dependency was searched
+    pub pkg_config_path: Option<String>,
+    /// The error returned by the [ProbeBackend] when looking for the main name
+    pub error: Error,
+}
+
+impl fmt::Display for MissingDependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let same_version = self.names.windows(2).all(|w| w[0].1 == w[1].1);
+        let names = self
+            .names
+            .iter()
+            .map(|(name, version)| {
+                if same_version {
+                    format!("`{}`", name)
+                } else {
+                    format!("`{}` (version {})", name, version)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "{}: tried {}", self.key, names)?;
+        if let (true, Some((_, version))) = (same_version, self.names.first()) {
+            write!(f, " with version {}", version)?;
+        }
+        if let Some(feature) = &self.feature {
+            write!(f, " (required by feature `{}`)", feature)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Default)]
 /// All the system dependencies retrieved by [Config::probe].
 pub struct Dependencies {
     libs: HashMap<String, Library>,
+    rerun_if_link_paths_changed: bool,
+    lockfile: Option<PathBuf>,
+    manifests: Vec<PathBuf>,
+    warnings: Vec<MetadataWarning>,
+    pkg_config_env_variables: Vec<String>,
 }
 
 impl Dependencies {
@@ -391,6 +900,11 @@ impl Dependencies {
         v
     }
 
+    /// Returns a vector of [Library::pc_files] of each library, removing duplicates.
+    pub fn all_pc_files(&self) -> Vec<&PathBuf> {
+        self.aggregate_path_buf(|l| &l.pc_files)
+    }
+
     /// Returns a vector of [Library::defines] of each library, removing duplicates.
     pub fn all_defines(&self) -> Vec<(&str, &Option<String>)> {
         let mut v = self
@@ -404,6 +918,19 @@ impl Dependencies {
         v
     }
 
+    /// Generate a Software Bill of Materials listing the system dependencies,
+    /// in the same order as [Dependencies::iter].
+    ///
+    /// # Arguments
+    ///
+    /// * `format`: the format of the document
+    /// * `package`: the name of the crate depending on those libraries
+    /// * `version`: the version of the crate
+    #[cfg(feature = "sbom")]
+    pub fn sbom(&self, format: SbomFormat, package: &str, version: &str) -> String {
+        sbom::generate(self, format, package, version)
+    }
+
     fn add(&mut self, name: &str, lib: Library) {
         self.libs.insert(name.to_string(), lib);
     }
@@ -446,6 +973,68 @@ impl Dependencies {
         }
     }
 
+    /// Generate all the cargo directives needed to build against the dependencies:
+    /// the link flags, the `rerun-if-*` directives and the `cfg` and environment variables
+    /// exported to the crate.
+    ///
+    /// The flags can be modified before being printed by the build script.
+    pub fn build_flags(&self) -> Result<BuildFlags, Error> {
+        let mut flags = self.gen_flags()?;
+
+        for manifest in &self.manifests {
+            flags.add(BuildFlag::RerunIfChanged(
+                manifest.to_string_lossy().to_string(),
+            ));
+        }
+
+        for (name, lib) in self.iter() {
+            flags.add(BuildFlag::Cfg(format!(
+                "system_deps_have_{}",
+                name.to_snake_case()
+            )));
+
+            if let Some(alternative) = &lib.alternative {
+                flags.add(BuildFlag::Cfg(format!(
+                    "system_deps_{}_is_{}",
+                    name.to_snake_case(),
+                    alternative.to_snake_case()
+                )));
+            }
+
+            for cfg in &lib.cfgs {
+                flags.add(BuildFlag::Cfg(cfg.clone()));
+            }
+
+            flags.add(BuildFlag::Env(
+                format!("SYSTEM_DEPS_{}_VERSION", name.to_shouty_snake_case()),
+                lib.version.clone(),
+            ));
+        }
+
+        Ok(flags)
+    }
+
+    /// Write all the cargo directives returned by [Dependencies::build_flags] to `w`.
+    ///
+    /// [Config::probe] writes them to the standard output, which is where cargo expects them.
+    ///
+    /// The [warnings](Dependencies::warnings) about the metadata are written first,
+    /// as `cargo:warning` directives.
+    pub fn emit_cargo_directives(&self, w: &mut impl std::io::Write) -> Result<(), Error> {
+        let flags = self.build_flags()?;
+        let error = |e| Error::FailToWrite("error writing cargo directives".into(), e);
+
+        for warning in self.warnings.iter() {
+            writeln!(w, "cargo:warning={}", warning).map_err(error)?;
+        }
+        write!(w, "{}", flags).map_err(error)
+    }
+
+    /// The suspicious declarations found in the metadata, see [Config::check_metadata]
+    pub fn warnings(&self) -> &[MetadataWarning] {
+        &self.warnings
+    }
+
     fn gen_flags(&self) -> Result<BuildFlags, Error> {
         let mut flags = BuildFlags::new();
         let mut include_paths = Vec::new();
@@ -488,13 +1077,51 @@ impl Dependencies {
             }
         }
 
+        // Rebuild if any of the resolved .pc files is modified, so upgrading a
+        // system library does not leave stale flags around.
+        for pc_file in self.all_pc_files() {
+            flags.add(BuildFlag::RerunIfChanged(
+                pc_file.to_string_lossy().to_string(),
+            ));
+        }
+
+        if let Some(lockfile) = &self.lockfile {
+            flags.add(BuildFlag::RerunIfChanged(
+                lockfile.to_string_lossy().to_string(),
+            ));
+        }
+
+        if self.rerun_if_link_paths_changed {
+            for link_path in self.all_link_paths() {
+                flags.add(BuildFlag::RerunIfChanged(
+                    link_path.to_string_lossy().to_string(),
+                ));
+            }
+        }
+
         // Export cargo:rerun-if-env-changed instructions for all env variables affecting system-deps behaviour
         flags.add(BuildFlag::RerunIfEnvChanged(
-            EnvVariable::new_build_internal(None),
+            EnvVariable::new_build_internal(None).to_string(),
+        ));
+        flags.add(BuildFlag::RerunIfEnvChanged(
+            EnvVariable::new_link(None).to_string(),
+        ));
+        flags.add(BuildFlag::RerunIfEnvChanged(
+            EnvVariable::Locked.to_string(),
+        ));
+        flags.add(BuildFlag::RerunIfEnvChanged(
+            EnvVariable::UpdateLock.to_string(),
+        ));
+        flags.add(BuildFlag::RerunIfEnvChanged(
+            EnvVariable::ProbeCache.to_string(),
         ));
-        flags.add(BuildFlag::RerunIfEnvChanged(EnvVariable::new_link(None)));
 
-        for (name, _lib) in self.libs.iter() {
+        // and the ones affecting pkg-config's behaviour
+        for var in &self.pkg_config_env_variables {
+            flags.add(BuildFlag::RerunIfEnvChanged(var.clone()));
+        }
+
+        for (name, _lib) in self.iter() {
             EnvVariable::set_rerun_if_changed_for_all_variants(&mut flags, name);
         }
 
@@ -559,6 +1186,9 @@ enum EnvVariable {
     BuildInternal(Option<String>),
     Link(Option<String>),
     LinkerArgs(String),
+    Locked,
+    UpdateLock,
+    ProbeCache,
 }
 
 impl EnvVariable {
@@ -609,13 +1239,16 @@ impl EnvVariable {
             EnvVariable::BuildInternal(_) => "BUILD_INTERNAL",
             EnvVariable::Link(_) => "LINK",
             EnvVariable::LinkerArgs(_) => "LDFLAGS",
+            EnvVariable::Locked => "LOCKED",
+            EnvVariable::UpdateLock => "UPDATE_LOCK",
+            EnvVariable::ProbeCache => "PROBE_CACHE",
         }
     }
 
     fn set_rerun_if_changed_for_all_variants(flags: &mut BuildFlags, name: &str) {
         #[inline]
         fn add_to_flags(flags: &mut BuildFlags, var: EnvVariable) {
-            flags.add(BuildFlag::RerunIfEnvChanged(var));
+            flags.add(BuildFlag::RerunIfEnvChanged(var.to_string()));
         }
         add_to_flags(flags, EnvVariable::new_lib(name));
         add_to_flags(flags, EnvVariable::new_lib_framework(name));
@@ -643,7 +1276,11 @@ impl fmt::Display for EnvVariable {
             | EnvVariable::Link(Some(lib)) => {
                 format!("{}_{}", lib.to_shouty_snake_case(), self.suffix())
             }
-            EnvVariable::BuildInternal(None) | EnvVariable::Link(None) => self.suffix().to_string(),
+            EnvVariable::BuildInternal(None)
+            | EnvVariable::Link(None)
+            | EnvVariable::Locked
+            | EnvVariable::UpdateLock
+            | EnvVariable::ProbeCache => self.suffix().to_string(),
         };
         write!(f, "SYSTEM_DEPS_{}", suffix)
     }
@@ -652,15 +1289,106 @@ impl fmt::Display for EnvVariable {
 type FnBuildInternal =
     dyn FnOnce(&str, &str) -> std::result::Result<Library, BuildInternalClosureError>;
 
+type FnBuildInternalWith = dyn FnOnce(
+    &BuildInternalContext<'_>,
+) -> std::result::Result<Library, BuildInternalClosureError>;
+
+enum BuildInternalFn {
+    Simple(Box<FnBuildInternal>),
+    WithContext(Box<FnBuildInternalWith>),
+}
+
+/// Information about the library to build passed to the closures registered
+/// using [Config::add_build_internal_with].
+pub struct BuildInternalContext<'a> {
+    /// The name of the `toml` key defining the dependency in `Cargo.toml`
+    pub key: String,
+    /// The name of the library as known by `pkg-config`
+    pub name: String,
+    /// The required version, see [the version format](index.html#version-format)
+    pub version: String,
+    /// The minimum version required
+    pub min_version: String,
+    /// The version the library has to be older than, if any
+    pub max_version: Option<String>,
+    /// The target triple the crate is built for, from the `TARGET` environment variable
+    pub target: Option<String>,
+    /// The directory where the build script should write its output, from the `OUT_DIR` environment variable
+    pub out_dir: Option<PathBuf>,
+    /// `true` if the library has been requested to be statically linked using `SYSTEM_DEPS_$NAME_LINK`
+    /// or `SYSTEM_DEPS_LINK`
+    pub statik: bool,
+    /// The dependencies which have already been resolved
+    pub libraries: &'a Dependencies,
+    /// The directories containing the `.pc` files of the libraries which have already been
+    /// built internally, to be used as `PKG_CONFIG_PATH` when building this one
+    pub pkg_config_path: Vec<PathBuf>,
+    /// The environment to read variables such as `CC` or `NUM_JOBS` from, see [Config::env_source]
+    pub env: &'a dyn EnvSource,
+}
+
+impl fmt::Debug for BuildInternalContext<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BuildInternalContext")
+            .field("key", &self.key)
+            .field("name", &self.name)
+            .field("version", &self.version)
+            .field("min_version", &self.min_version)
+            .field("max_version", &self.max_version)
+            .field("target", &self.target)
+            .field("out_dir", &self.out_dir)
+            .field("statik", &self.statik)
+            .field("libraries", &self.libraries)
+            .field("pkg_config_path", &self.pkg_config_path)
+            .finish_non_exhaustive()
+    }
+}
+
 /// Structure used to configure `metadata` before starting to probe for dependencies
 pub struct Config {
     env: EnvVariables,
-    build_internals: HashMap<String, Box<FnBuildInternal>>,
+    // `None` to run `pkg-config`
+    backend: Option<Box<dyn ProbeBackend>>,
+    build_internals: HashMap<String, BuildInternalFn>,
+    internal_pc_dirs: Vec<PathBuf>,
+    rerun_if_link_paths_changed: bool,
+    dependencies: Vec<(String, DependencySpec)>,
+    manifest_path: Option<PathBuf>,
+    metadata_table: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// A system dependency declared using [Config::add_dependency], with the same settings
+/// as the ones available in the `Cargo.toml` metadata.
+pub struct DependencySpec {
+    /// The version required, see [the version format](index.html#version-format)
+    pub version: Option<String>,
+    /// The name of the library if it differs from the dependency name
+    pub name: Option<String>,
+    /// Other names the library may be available under
+    pub fallback_names: Option<Vec<String>>,
+    /// Do not fail if the library is not found
+    pub optional: bool,
+    /// Only look for the library if this feature is enabled
+    pub feature: Option<String>,
+    /// Only look for the library if this `cfg()` expression matches the target, such as `cfg(unix)`
+    pub cfg: Option<String>,
+    /// Keys of the dependencies which have to be resolved, and internally built if needed, before this one
+    pub requires: Vec<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Self::new_with_env(EnvVariables::Environment)
+        Self {
+            env: EnvVariables(Box::new(ProcessEnv)),
+            backend: None,
+            build_internals: HashMap::new(),
+            internal_pc_dirs: Vec::new(),
+            rerun_if_link_paths_changed: false,
+            dependencies: Vec::new(),
+            manifest_path: None,
+            metadata_table: "system-deps".to_string(),
+        }
     }
 }
 
@@ -670,31 +1398,35 @@ impl Config {
         Self::default()
     }
 
-    fn new_with_env(env: EnvVariables) -> Self {
-        Self {
-            env,
-            build_internals: HashMap::new(),
-        }
-    }
-
     /// Probe all libraries configured in the Cargo.toml
     /// `[package.metadata.system-deps]` section.
     ///
     /// The returned hash is using the `toml` key defining the dependency as key.
+    ///
+    /// Dependencies are looked for concurrently using up to `NUM_JOBS` threads,
+    /// the result being the same as if they were looked for one after the other.
+    /// The ones requiring a dependency which may be built internally are looked for
+    /// once it has been resolved.
     pub fn probe(self) -> Result<Dependencies, Error> {
         let libraries = self.probe_full()?;
-        let flags = libraries.gen_flags()?;
 
         // Output cargo flags
-        println!("{}", flags);
-
-        for (name, _) in libraries.iter() {
-            println!("cargo:rustc-cfg=system_deps_have_{}", name.to_snake_case());
-        }
+        libraries.emit_cargo_directives(&mut std::io::stdout())?;
 
         Ok(libraries)
     }
 
+    /// Probe all libraries configured in the Cargo.toml
+    /// `[package.metadata.system-deps]` section, without having any side effect.
+    ///
+    /// Contrary to [Config::probe], nothing is printed so the build script can inspect the
+    /// dependencies before emitting the cargo directives using [Dependencies::build_flags]
+    /// or [Dependencies::emit_cargo_directives].
+    /// The lock file is checked if `SYSTEM_DEPS_LOCKED` is enabled but never updated, and no SBOM is written.
+    pub fn resolve(mut self) -> Result<Dependencies, Error> {
+        self.resolve_dependencies()
+    }
+
     /// Add hook so system-deps can internally build library `name` if requested by user.
     ///
     /// It will only be triggered if the environment variable
@@ -712,35 +1444,236 @@ impl Config {
         F: 'static + FnOnce(&str, &str) -> std::result::Result<Library, BuildInternalClosureError>,
     {
         let mut build_internals = self.build_internals;
-        build_internals.insert(name.to_string(), Box::new(func));
+        build_internals.insert(name.to_string(), BuildInternalFn::Simple(Box::new(func)));
 
         Self {
-            env: self.env,
             build_internals,
+            ..self
         }
     }
 
+    /// Add hook so system-deps can internally build library `name` if requested by user,
+    /// like [Config::add_build_internal].
+    ///
+    /// The closure receives a [BuildInternalContext] describing the library to build,
+    /// including the other dependencies which have already been resolved, so it can be built
+    /// against them.
+    ///
+    /// # Arguments
+    /// * `name`: the name of the library, as defined in `Cargo.toml`
+    /// * `func`: closure called when internally building the library.
+    pub fn add_build_internal_with<F>(mut self, name: &str, func: F) -> Self
+    where
+        F: 'static
+            + FnOnce(
+                &BuildInternalContext<'_>,
+            ) -> std::result::Result<Library, BuildInternalClosureError>,
+    {
+        self.build_internals.insert(
+            name.to_string(),
+            BuildInternalFn::WithContext(Box::new(func)),
+        );
+        self
+    }
+
+    /// Declare a dependency from the build script, in addition to the ones defined in
+    /// the `Cargo.toml` metadata.
+    ///
+    /// If the metadata already defines a dependency using the same name, it is replaced.
+    /// The `Cargo.toml` does not need to have any `system-deps` metadata if all the dependencies
+    /// are declared using this method.
+    ///
+    /// # Arguments
+    /// * `name`: the name of the dependency, used the same way as the `toml` key defining it in `Cargo.toml`
+    /// * `spec`: the settings of the dependency
+    pub fn add_dependency(mut self, name: &str, spec: DependencySpec) -> Self {
+        self.dependencies.retain(|(n, _)| n != name);
+        self.dependencies.push((name.to_string(), spec));
+        self
+    }
+
+    /// Read the dependencies from the manifest at `path` rather than from the
+    /// `Cargo.toml` of the crate being built.
+    pub fn manifest_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.manifest_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Read the dependencies from the `[package.metadata.$name]` section of the manifest
+    /// rather than from `[package.metadata.system-deps]`.
+    ///
+    /// Dependencies inherited from the workspace are then defined in `[workspace.metadata.$name]`.
+    pub fn metadata_table(mut self, name: &str) -> Self {
+        self.metadata_table = name.to_string();
+        self
+    }
+
+    /// Read the environment variables from `env` rather than from the process environment.
+    ///
+    /// This includes the `SYSTEM_DEPS_*` variables, the ones set by cargo, such as
+    /// `CARGO_MANIFEST_DIR`, `CARGO_FEATURE_*` or `TARGET`, and the ones read by pkg-config,
+    /// such as `PKG_CONFIG_PATH`. The `pkg-config` crate only reads the process environment,
+    /// so its variables are temporarily set to their value in `env` while it runs.
+    pub fn env_source<E: EnvSource + 'static>(mut self, env: E) -> Self {
+        self.env = EnvVariables(Box::new(env));
+        self
+    }
+
+    /// Retrieve the library settings using `backend` rather than by running `pkg-config`.
+    pub fn probe_backend<B: ProbeBackend + 'static>(mut self, backend: B) -> Self {
+        self.backend = Some(Box::new(backend));
+        self
+    }
+
+    /// Also rebuild the crate if the content of any of the directories where libraries
+    /// are searched for is modified.
+    ///
+    /// By default only the `.pc` files the libraries have been resolved from are tracked.
+    /// Enabling this makes sure the crate is rebuilt if the library itself is replaced,
+    /// at the cost of possibly spurious rebuilds when unrelated files are
+    /// added to those directories.
+    pub fn rerun_if_link_paths_changed(mut self, enabled: bool) -> Self {
+        self.rerun_if_link_paths_changed = enabled;
+        self
+    }
+
+    /// Check the metadata defining the dependencies without looking for them.
+    ///
+    /// Errors in the metadata are returned as [Error::InvalidManifest], while
+    /// declarations which are likely to be mistakes, such as a `feature` which is not
+    /// declared in `[features]`, are returned as warnings.
+    /// Those are also reported by [Dependencies::warnings] and printed by [Config::probe].
+    pub fn check_metadata(&self) -> Result<Vec<MetadataWarning>, Error> {
+        let (_, metadata) = self.load_metadata()?;
+        Ok(metadata.lint())
+    }
+
     fn probe_full(mut self) -> Result<Dependencies, Error> {
+        let mut libraries = self.resolve_dependencies()?;
+        self.update_lockfile(&mut libraries)?;
+        #[cfg(feature = "sbom")]
+        self.write_sbom(&libraries)?;
+
+        Ok(libraries)
+    }
+
+    fn resolve_dependencies(&mut self) -> Result<Dependencies, Error> {
         let mut libraries = self.probe_pkg_config()?;
         libraries.override_from_flags(&self.env);
+        libraries.rerun_if_link_paths_changed = self.rerun_if_link_paths_changed;
+        libraries.pkg_config_env_variables = pc_files::env_variables(self.env.0.as_ref());
+        self.check_lockfile(&mut libraries)?;
 
         Ok(libraries)
     }
 
-    fn probe_pkg_config(&mut self) -> Result<Dependencies, Error> {
+    #[cfg(feature = "sbom")]
+    fn write_sbom(&self, libraries: &Dependencies) -> Result<(), Error> {
+        let out_dir = match self.env.get("OUT_DIR") {
+            Some(dir) => dir,
+            // Not running from a build script
+            None => return Ok(()),
+        };
+        let package = self.env.get("CARGO_PKG_NAME").unwrap_or_default();
+        let version = self.env.get("CARGO_PKG_VERSION").unwrap_or_default();
+
+        sbom::write(libraries, Path::new(&out_dir), &package, &version)
+    }
+
+    fn lockfile_path(&self) -> Result<(PathBuf, String), Error> {
         let dir = self
             .env
             .get("CARGO_MANIFEST_DIR")
-            .ok_or_else(|| Error::InvalidMetadata("$CARGO_MANIFEST_DIR not set".into()))?;
-        let mut path = PathBuf::from(dir);
-        path.push("Cargo.toml");
+            .ok_or_else(|| Error::MissingEnvVariable("CARGO_MANIFEST_DIR".into()))?;
+        let package = self
+            .env
+            .get("CARGO_PKG_NAME")
+            .ok_or_else(|| Error::MissingEnvVariable("CARGO_PKG_NAME".into()))?;
 
-        println!("cargo:rerun-if-changed={}", &path.to_string_lossy());
+        Ok((Lockfile::path(Path::new(&dir)), package))
+    }
 
-        let metadata = MetaData::from_file(&path)?;
+    fn check_lockfile(&self, libraries: &mut Dependencies) -> Result<(), Error> {
+        if !self.env.enabled(&EnvVariable::Locked)? || self.env.enabled(&EnvVariable::UpdateLock)? {
+            return Ok(());
+        }
+
+        let (path, package) = self.lockfile_path()?;
+        let lockfile = Lockfile::load(path)?;
+        lockfile.check(&package, libraries)?;
+        libraries.lockfile = Some(lockfile.file_path().to_path_buf());
+
+        Ok(())
+    }
+
+    fn update_lockfile(&self, libraries: &mut Dependencies) -> Result<(), Error> {
+        if !self.env.enabled(&EnvVariable::UpdateLock)? {
+            return Ok(());
+        }
+
+        let (path, package) = self.lockfile_path()?;
+        let lockfile = Lockfile::update(path, &package, libraries)?;
+        libraries.lockfile = Some(lockfile.file_path().to_path_buf());
+
+        Ok(())
+    }
+
+    // Load the metadata from the manifest, and the dependencies declared by the build script
+    fn load_metadata(&self) -> Result<(PathBuf, MetaData), Error> {
+        let path = match &self.manifest_path {
+            Some(path) => path.clone(),
+            None => {
+                let dir = self
+                    .env
+                    .get("CARGO_MANIFEST_DIR")
+                    .ok_or_else(|| Error::MissingEnvVariable("CARGO_MANIFEST_DIR".into()))?;
+                Path::new(&dir).join("Cargo.toml")
+            }
+        };
 
-        let mut libraries = Dependencies::default();
+        let manifest = metadata::Manifest::read(&path)?;
+        let mut metadata = MetaData::from_manifest(
+            &manifest,
+            &self.metadata_table,
+            self.dependencies.is_empty(),
+        )?;
+
+        // Dependencies declared by the build script replace the ones from the metadata
+        for (name, spec) in self.dependencies.iter() {
+            let dep = metadata::Dependency::from_spec(name, spec).map_err(|error| {
+                Error::InvalidManifest {
+                    path: path.clone(),
+                    error,
+                    location: None,
+                }
+            })?;
+            metadata.deps.retain(|d| &d.key != name);
+            metadata.deps.push(dep);
+        }
+
+        let table_key = format!("package.metadata.{}", self.metadata_table);
+        metadata
+            .sort_by_requires(&table_key)
+            .map_err(|e| manifest.error(e))?;
+
+        Ok((path, metadata))
+    }
+
+    fn probe_pkg_config(&mut self) -> Result<Dependencies, Error> {
+        let (path, mut metadata) = self.load_metadata()?;
+        let warnings = metadata.lint();
 
+        let mut libraries = Dependencies {
+            manifests: std::iter::once(path)
+                .chain(metadata.workspace_manifest.take())
+                .collect(),
+            warnings,
+            ..Default::default()
+        };
+        let mut missing = Vec::new();
+
+        // Gather the settings of each dependency to look for
+        let mut plans = Vec::new();
         for dep in metadata.deps.iter() {
             if let Some(cfg) = &dep.cfg {
                 // Check if `cfg()` expression matches the target settings
@@ -749,6 +1682,15 @@ impl Config {
                 }
             }
 
+            // Apply the settings of the cfg() sub-tables matching the target
+            let mut dep = dep.clone();
+            for o in std::mem::take(&mut dep.cfg_overrides) {
+                if self.check_cfg(&o.cfg)? {
+                    dep.merge_cfg_override(&o);
+                }
+            }
+            let dep = &dep;
+
             let mut enabled_feature_overrides = Vec::new();
 
             for o in dep.version_overrides.iter() {
@@ -768,32 +1710,28 @@ impl Config {
             let lib_name;
             let fallback_lib_names;
             let optional;
+            let feature;
+            let mut version_override = None;
+            let resolved_version;
             if enabled_feature_overrides.is_empty() {
                 version = dep.version.as_deref();
                 lib_name = dep.lib_name();
                 fallback_lib_names = dep.fallback_names.as_deref().unwrap_or(&[]);
                 optional = dep.optional;
+                feature = dep.feature.as_deref();
             } else {
-                enabled_feature_overrides.sort_by(|a, b| {
-                    fn min_version(r: metadata::VersionRange) -> &str {
-                        match r.start_bound() {
-                            std::ops::Bound::Unbounded => unreachable!(),
-                            std::ops::Bound::Excluded(_) => unreachable!(),
-                            std::ops::Bound::Included(b) => b,
-                        }
-                    }
-
-                    let a = min_version(metadata::parse_version(&a.version));
-                    let b = min_version(metadata::parse_version(&b.version));
-
-                    version_compare::compare(a, b)
-                        .expect("failed to compare versions")
-                        .ord()
-                        .expect("invalid version")
-                });
-                let highest = enabled_feature_overrides.into_iter().last().unwrap();
-
-                version = Some(highest.version.as_str());
+                // The required version must match all the enabled overrides, while the
+                // other settings come from the one requiring the highest version.
+                let versions = enabled_feature_overrides
+                    .iter()
+                    .map(|o| o.version.as_str())
+                    .collect::<Vec<_>>();
+                let (range, strictest) = metadata::intersect_versions(&versions)
+                    .map_err(|e| Error::invalid_metadata(Some(&dep.key), e))?;
+                let highest = enabled_feature_overrides[strictest];
+
+                resolved_version = range;
+                version = Some(resolved_version.as_str());
                 lib_name = highest.name.as_deref().unwrap_or(dep.lib_name());
                 fallback_lib_names = highest
                     .fallback_names
@@ -801,11 +1739,57 @@ impl Config {
                     .or(dep.fallback_names.as_deref())
                     .unwrap_or(&[]);
                 optional = highest.optional.unwrap_or(dep.optional);
+                feature = Some(highest.key.as_str());
+                version_override = Some(highest.key.as_str());
             };
 
-            let version = version.ok_or_else(|| {
-                Error::InvalidMetadata(format!("No version defined for {}", dep.key))
-            })?;
+            // The libraries which can fulfill the dependency: the alternatives enabled
+            // in an `any-of` group, or the dependency itself.
+            let candidates = if dep.alternatives.is_empty() {
+                let version = version
+                    .ok_or_else(|| Error::invalid_metadata(Some(&dep.key), "no version defined"))?;
+
+                vec![Candidate {
+                    name: lib_name.to_string(),
+                    version: version.to_string(),
+                    fallback_names: fallback_lib_names.to_vec(),
+                    alternative: None,
+                }]
+            } else {
+                dep.alternatives
+                    .iter()
+                    .filter(|a| match &a.feature {
+                        Some(f) => self.has_feature(f),
+                        None => true,
+                    })
+                    .map(|a| {
+                        let version = a.version.as_deref().or(version).ok_or_else(|| {
+                            Error::invalid_metadata(
+                                Some(&dep.key),
+                                format!("no version defined for {}", a.name),
+                            )
+                        })?;
+
+                        Ok(Candidate {
+                            name: a.name.clone(),
+                            version: version.to_string(),
+                            fallback_names: a.fallback_names.clone().unwrap_or_default(),
+                            alternative: Some(a.name.clone()),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?
+            };
+
+            if candidates.is_empty() {
+                // None of the alternatives are enabled
+                if optional {
+                    continue;
+                }
+                return Err(Error::invalid_metadata(
+                    Some(&dep.key),
+                    "none of the `any-of` alternatives is enabled",
+                ));
+            }
 
             let name = &dep.key;
             let build_internal = self.get_build_internal_status(name)?;
@@ -816,56 +1800,158 @@ impl Config {
                 .has_value(&EnvVariable::new_link(Some(name)), "static")
                 || self.env.has_value(&EnvVariable::new_link(None), "static");
 
-            let mut library = if self.env.contains(&EnvVariable::new_no_pkg_config(name)) {
-                Library::from_env_variables(name)
+            let no_pkg_config = self.env.contains(&EnvVariable::new_no_pkg_config(name));
+            let probe = !no_pkg_config && build_internal != BuildInternal::Always;
+            // The dependencies are sorted so the ones it requires have already been planned
+            let deferred = plans.iter().any(|p: &Plan| {
+                dep.requires.contains(&p.key)
+                    && (p.build_internal != BuildInternal::Never || p.deferred)
+            });
+
+            plans.push(Plan {
+                key: name.clone(),
+                candidates,
+                optional,
+                feature: feature.map(|f| f.to_string()),
+                version_override: version_override.map(|k| k.to_string()),
+                cfgs_if_found: dep.cfgs_if_found.clone(),
+                statik,
+                build_internal,
+                no_pkg_config,
+                probe,
+                deferred,
+            });
+        }
+
+        // Look for the libraries, concurrently as they are independent from each other
+        let jobs = plans
+            .iter()
+            .filter(|plan| plan.probe && !plan.deferred)
+            .map(|plan| (plan.candidates.as_slice(), plan.statik))
+            .collect::<Vec<_>>();
+        let num_jobs = self
+            .env
+            .get("NUM_JOBS")
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(1);
+        let env = self.env.0.as_ref();
+        let mut probed = match &self.backend {
+            Some(backend) => probe_all(backend.as_ref(), env, &jobs, num_jobs),
+            None => {
+                let cache = if self.env.enabled(&EnvVariable::ProbeCache)? {
+                    self.env
+                        .get("OUT_DIR")
+                        .and_then(|out_dir| cache::ProbeCache::new(Path::new(&out_dir), env))
+                } else {
+                    None
+                };
+                match &cache {
+                    Some(cache) => probe_all(&cache::CachedBackend { cache }, env, &jobs, num_jobs),
+                    None => probe_all(&PkgConfigBackend, env, &jobs, num_jobs),
+                }
+            }
+        }
+        .into_iter();
+
+        for plan in plans {
+            let name = &plan.key;
+            let candidates = &plan.candidates;
+            let first = &candidates[0];
+            let statik = plan.statik;
+            let build_internal = plan.build_internal;
+
+            let (candidate, mut library) = if plan.no_pkg_config {
+                (first, Library::from_env_variables(name))
             } else if build_internal == BuildInternal::Always {
-                self.call_build_internal(lib_name, version)?
+                (
+                    first,
+                    self.call_build_internal(&first.name, name, first, statik, &libraries)?,
+                )
             } else {
-                let mut config = pkg_config::Config::new();
-                config
-                    .print_system_libs(false)
-                    .cargo_metadata(false)
-                    .range_version(metadata::parse_version(version))
-                    .statik(statik);
-
-                match Self::probe_with_fallback(config, lib_name, fallback_lib_names) {
-                    Ok((lib_name, lib)) => Library::from_pkg_config(lib_name, lib),
+                let result = if plan.deferred {
+                    self.probe_deferred(candidates, statik)
+                } else {
+                    probed.next().expect("missing probe result")
+                };
+                match result {
+                    Ok((i, lib)) => (&candidates[i], lib),
                     Err(e) => {
+                        let e = e.with_key(name);
                         if build_internal == BuildInternal::Auto {
                             // Try building the lib internally as a fallback
-                            self.call_build_internal(name, version)?
-                        } else if optional {
+                            (
+                                first,
+                                self.call_build_internal(name, name, first, statik, &libraries)?,
+                            )
+                        } else if plan.optional {
                             // If the dep is optional just skip it
                             continue;
                         } else {
-                            return Err(e.into());
+                            // Keep looking for the other deps so all the missing ones are reported at once
+                            missing.push(MissingDependency {
+                                key: name.to_string(),
+                                names: candidates
+                                    .iter()
+                                    .flat_map(|c| {
+                                        std::iter::once(&c.name)
+                                            .chain(c.fallback_names.iter())
+                                            .map(move |n| (n.clone(), c.version.clone()))
+                                    })
+                                    .collect(),
+                                feature: plan.feature.clone(),
+                                pkg_config_path: pc_files::var(
+                                    self.env.0.as_ref(),
+                                    "PKG_CONFIG_PATH",
+                                ),
+                                error: e,
+                            });
+                            continue;
                         }
                     }
                 }
             };
 
             library.statik = statik;
+            library.requested_name = candidate.name.clone();
+            library.required_version = candidate.version.clone();
+            library.version_override = plan.version_override.clone();
+            library.alternative = candidate.alternative.clone();
+            library.cfgs = plan.cfgs_if_found.clone();
 
             libraries.add(name, library);
         }
-        Ok(libraries)
+
+        match missing.len() {
+            0 => Ok(libraries),
+            1 => Err(missing.remove(0).error),
+            _ => Err(Error::MultipleMissing(missing)),
+        }
     }
 
-    fn probe_with_fallback<'a>(
-        config: pkg_config::Config,
-        name: &'a str,
-        fallback_names: &'a [String],
-    ) -> Result<(&'a str, pkg_config::Library), pkg_config::Error> {
-        let error = match config.probe(name) {
-            Ok(x) => return Ok((name, x)),
-            Err(e) => e,
-        };
-        for name in fallback_names {
-            if let Ok(library) = config.probe(name) {
-                return Ok((name, library));
+    // Look for a library requiring dependencies which may have been built internally,
+    // letting pkg-config find their `.pc` files
+    fn probe_deferred(
+        &self,
+        candidates: &[Candidate],
+        statik: bool,
+    ) -> Result<(usize, Library), Error> {
+        let env = self.env.0.as_ref();
+        match &self.backend {
+            Some(backend) => probe_candidates(backend.as_ref(), env, candidates, statik),
+            None if self.internal_pc_dirs.is_empty() => {
+                probe_candidates(&PkgConfigBackend, env, candidates, statik)
+            }
+            None => {
+                let pkg_config = self
+                    .internal_pc_dirs
+                    .iter()
+                    .fold(InternalPkgConfig::new(), |pkg_config, dir| {
+                        pkg_config.pc_dir(dir)
+                    });
+                let backend = backend::InternalDirsBackend(pkg_config);
+                probe_candidates(&backend, env, candidates, statik)
             }
         }
-        Err(error)
     }
 
     fn get_build_internal_env_var(&self, var: EnvVariable) -> Result<Option<BuildInternal>, Error> {
@@ -892,28 +1978,24 @@ impl Config {
         }
     }
 
-    fn call_build_internal(&mut self, name: &str, version_str: &str) -> Result<Library, Error> {
-        let lib = match self.build_internals.remove(name) {
-            Some(f) => f(name, version_str)
-                .map_err(|e| Error::BuildInternalClosureError(name.into(), e))?,
-            None => {
-                return Err(Error::BuildInternalNoClosure(
-                    name.into(),
-                    version_str.into(),
-                ))
-            }
-        };
-
-        // Check that the lib built internally matches the required version
+    fn call_build_internal(
+        &mut self,
+        name: &str,
+        key: &str,
+        candidate: &Candidate,
+        statik: bool,
+        libraries: &Dependencies,
+    ) -> Result<Library, Error> {
+        let version_str = candidate.version.as_str();
         let version = metadata::parse_version(version_str);
-        fn min_version(r: metadata::VersionRange) -> &str {
+        fn min_version(r: metadata::VersionRange<'_>) -> &str {
             match r.start_bound() {
                 std::ops::Bound::Unbounded => unreachable!(),
                 std::ops::Bound::Excluded(_) => unreachable!(),
                 std::ops::Bound::Included(b) => b,
             }
         }
-        fn max_version(r: metadata::VersionRange) -> Option<&str> {
+        fn max_version(r: metadata::VersionRange<'_>) -> Option<&str> {
             match r.end_bound() {
                 std::ops::Bound::Included(_) => unreachable!(),
                 std::ops::Bound::Unbounded => None,
@@ -921,22 +2003,56 @@ impl Config {
             }
         }
 
+        let lib = match self.build_internals.remove(name) {
+            Some(BuildInternalFn::Simple(f)) => f(name, version_str),
+            Some(BuildInternalFn::WithContext(f)) => f(&BuildInternalContext {
+                key: key.to_string(),
+                name: candidate.name.to_string(),
+                version: version_str.to_string(),
+                min_version: min_version(version.clone()).to_string(),
+                max_version: max_version(version.clone()).map(|v| v.to_string()),
+                target: self.env.get("TARGET"),
+                out_dir: self.env.get("OUT_DIR").map(PathBuf::from),
+                statik,
+                libraries,
+                pkg_config_path: self.internal_pc_dirs.clone(),
+                env: self.env.0.as_ref(),
+            }),
+            None => {
+                return Err(Error::BuildInternalNoClosure {
+                    key: key.into(),
+                    name: candidate.name.clone(),
+                    version: version_str.into(),
+                })
+            }
+        }
+        .map_err(|error| Error::BuildInternalClosureError {
+            name: name.into(),
+            error,
+        })?;
+
+        // Check that the lib built internally matches the required version
+        let wrong_version = |lib: Library| Error::BuildInternalWrongVersion {
+            key: key.into(),
+            name: name.into(),
+            version: lib.version,
+            required_version: version_str.into(),
+        };
         let min = min_version(version.clone());
         if version_compare::compare(&lib.version, min) == Ok(version_compare::Cmp::Lt) {
-            return Err(Error::BuildInternalWrongVersion(
-                name.into(),
-                lib.version,
-                version_str.into(),
-            ));
+            return Err(wrong_version(lib));
         }
 
         if let Some(max) = max_version(version) {
             if version_compare::compare(&lib.version, max) == Ok(version_compare::Cmp::Ge) {
-                return Err(Error::BuildInternalWrongVersion(
-                    name.into(),
-                    lib.version,
-                    version_str.into(),
-                ));
+                return Err(wrong_version(lib));
+            }
+        }
+
+        // Let the libraries built afterward find this one
+        if let Some(dir) = lib.pc_files.first().and_then(|pc| pc.parent()) {
+            if !self.internal_pc_dirs.iter().any(|d| d == dir) {
+                self.internal_pc_dirs.push(dir.to_path_buf());
             }
         }
 
@@ -948,6 +2064,14 @@ impl Config {
         self.env.contains(var)
     }
 
+    // Check if the `CARGO_CFG_$KEY` comma separated list contains `value`
+    fn has_cargo_cfg(&self, key: &str, value: &str) -> bool {
+        match self.env.get(cargo_cfg_var(key).as_str()) {
+            Some(values) => values.split(',').any(|v| v == value),
+            None => false,
+        }
+    }
+
     fn check_cfg(&self, cfg: &cfg_expr::Expression) -> Result<bool, Error> {
         use cfg_expr::{targets::get_builtin_target_by_triple, Predicate};
 
@@ -956,26 +2080,156 @@ impl Config {
             .get("TARGET")
             .expect("no TARGET env variable defined");
 
-        let res = if let Some(target) = get_builtin_target_by_triple(&target) {
-            cfg.eval(|pred| match pred {
-                Predicate::Target(tp) => Some(tp.matches(target)),
-                _ => None,
-            })
-        } else {
-            // Attempt to parse the triple, the target is not an official builtin
-            let triple: cfg_expr::target_lexicon::Triple = target.parse().unwrap_or_else(|e| panic!("TARGET {} is not a builtin target, and it could not be parsed as a valid triplet: {}", target, e));
+        // The target is either a builtin one or a triple which could be parsed
+        enum Target {
+            Builtin(&'static cfg_expr::targets::TargetInfo),
+            Triple(cfg_expr::target_lexicon::Triple),
+        }
 
-            cfg.eval(|pred| match pred {
-                Predicate::Target(tp) => Some(tp.matches(&triple)),
-                _ => None,
-            })
+        let target = match get_builtin_target_by_triple(&target) {
+            Some(builtin) => Target::Builtin(builtin),
+            // Attempt to parse the triple, the target is not an official builtin
+            None => Target::Triple(target.parse().unwrap_or_else(|e| panic!("TARGET {} is not a builtin target, and it could not be parsed as a valid triplet: {}", target, e))),
         };
 
+        let res = cfg.eval(|pred| match pred {
+            Predicate::Target(tp) => Some(match &target {
+                Target::Builtin(builtin) => tp.matches(*builtin),
+                Target::Triple(triple) => tp.matches(triple),
+            }),
+            Predicate::Feature(feature) => Some(self.has_feature(feature)),
+            // Those are only known through the cfg cargo passes to build scripts,
+            // which also include the custom ones defined using `--cfg` in RUSTFLAGS.
+            Predicate::TargetFeature(feature) => {
+                Some(self.has_cargo_cfg("target_feature", feature))
+            }
+            Predicate::DebugAssertions => Some(self.env.contains("CARGO_CFG_DEBUG_ASSERTIONS")),
+            Predicate::Flag(flag) => Some(self.env.contains(cargo_cfg_var(flag).as_str())),
+            Predicate::KeyValue { key, val } => Some(self.has_cargo_cfg(key, val)),
+            _ => None,
+        });
+
         res.ok_or_else(|| Error::UnsupportedCfg(cfg.original().to_string()))
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+// A library which can be used to fulfill a dependency
+struct Candidate {
+    name: String,
+    version: String,
+    fallback_names: Vec<String>,
+    // Name of the alternative, if the dependency is an `any-of` group
+    alternative: Option<String>,
+}
+
+// The settings of a dependency to resolve
+struct Plan {
+    key: String,
+    candidates: Vec<Candidate>,
+    optional: bool,
+    feature: Option<String>,
+    version_override: Option<String>,
+    cfgs_if_found: Vec<String>,
+    statik: bool,
+    build_internal: BuildInternal,
+    no_pkg_config: bool,
+    // The library has to be looked for using the backend
+    probe: bool,
+    // The library requires dependencies which may be built internally, so has to be
+    // looked for after them rather than along with the other libraries
+    deferred: bool,
+}
+
+// Probe each candidate in order, returning the index of the first one which has been found
+fn probe_candidates(
+    backend: &dyn ProbeBackend,
+    env: &dyn EnvSource,
+    candidates: &[Candidate],
+    statik: bool,
+) -> Result<(usize, Library), Error> {
+    let mut error = None;
+
+    for (i, candidate) in candidates.iter().enumerate() {
+        match probe_with_fallback(backend, env, candidate, statik) {
+            Ok(lib) => return Ok((i, lib)),
+            Err(e) => {
+                // Report the error of the first candidate
+                error.get_or_insert(e);
+            }
+        }
+    }
+
+    Err(error.expect("no candidate"))
+}
+
+fn probe_with_fallback(
+    backend: &dyn ProbeBackend,
+    env: &dyn EnvSource,
+    candidate: &Candidate,
+    statik: bool,
+) -> Result<Library, Error> {
+    let error = match backend.probe(&candidate.name, &candidate.version, statik, env) {
+        Ok(x) => return Ok(x),
+        Err(e) => e,
+    };
+    for name in &candidate.fallback_names {
+        if let Ok(library) = backend.probe(name, &candidate.version, statik, env) {
+            return Ok(library);
+        }
+    }
+    Err(error)
+}
+
+// Probe the candidates of each job using up to `num_jobs` threads.
+// The results are returned in the same order as the jobs.
+fn probe_all(
+    backend: &dyn ProbeBackend,
+    env: &dyn EnvSource,
+    jobs: &[(&[Candidate], bool)],
+    num_jobs: usize,
+) -> Vec<Result<(usize, Library), Error>> {
+    let num_threads = num_jobs.min(jobs.len());
+    if num_threads <= 1 {
+        return jobs
+            .iter()
+            .map(|(candidates, statik)| probe_candidates(backend, env, candidates, *statik))
+            .collect();
+    }
+
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let mut results = std::thread::scope(|scope| {
+        let threads = (0..num_threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let (candidates, statik) = match jobs.get(i) {
+                            Some(job) => job,
+                            None => break,
+                        };
+                        results.push((i, probe_candidates(backend, env, candidates, *statik)));
+                    }
+                    results
+                })
+            })
+            .collect::<Vec<_>>();
+
+        threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect::<Vec<_>>()
+    });
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+fn cargo_cfg_var(key: &str) -> String {
+    format!("CARGO_CFG_{}", key.to_uppercase())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 /// From where the library settings have been retrieved
 pub enum Source {
     /// Settings have been retrieved from `pkg-config`
@@ -984,7 +2238,7 @@ pub enum Source {
     EnvVariables,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 /// Internal library name and if a static library is available on the system
 pub struct InternalLib {
     /// Name of the library
@@ -994,7 +2248,7 @@ pub struct InternalLib {
 }
 
 impl InternalLib {
-    fn new(name: String, is_static_available: bool) -> Self {
+    pub(crate) fn new(name: String, is_static_available: bool) -> Self {
         InternalLib {
             name,
             is_static_available,
@@ -1002,11 +2256,63 @@ impl InternalLib {
     }
 }
 
-#[derive(Debug)]
+// Directories of the system libraries, which should only be linked dynamically.
+// taken from: https://github.com/rust-lang/pkg-config-rs/blob/54325785816695df031cef3b26b6a9a203bbc01b/src/lib.rs#L502
+fn system_roots(env: &dyn EnvSource) -> Vec<PathBuf> {
+    if cfg!(target_os = "macos") {
+        vec![PathBuf::from("/Library"), PathBuf::from("/System")]
+    } else {
+        let sysroot = pc_files::var(env, "PKG_CONFIG_SYSROOT_DIR")
+            .or_else(|| env.var("SYSROOT"))
+            .map(PathBuf::from);
+
+        if cfg!(target_os = "windows") {
+            if let Some(sysroot) = sysroot {
+                vec![sysroot]
+            } else {
+                vec![]
+            }
+        } else {
+            vec![sysroot.unwrap_or_else(|| PathBuf::from("/usr"))]
+        }
+    }
+}
+
+fn is_static_available(name: &str, link_paths: &[PathBuf], system_roots: &[PathBuf]) -> bool {
+    let libnames = {
+        let mut names = vec![format!("lib{}.a", name)];
+
+        if cfg!(target_os = "windows") {
+            names.push(format!("{}.lib", name));
+        }
+
+        names
+    };
+
+    link_paths.iter().any(|dir| {
+        let library_exists = libnames.iter().any(|libname| dir.join(libname).exists());
+        library_exists && !system_roots.iter().any(|sys| dir.starts_with(sys))
+    })
+}
+
+#[derive(Debug, Clone)]
 /// A system dependency
 pub struct Library {
-    /// Name of the library
+    /// Name of the library, which may be one of the fallback names if the
+    /// requested one has not been found
     pub name: String,
+    /// Name of the library as requested in `Cargo.toml`, taking the
+    /// selected version override into account
+    pub requested_name: String,
+    /// Version requirement the library has been checked against, matching all the
+    /// enabled [version overrides](index.html#feature-versions)
+    pub required_version: String,
+    /// Key of the version override selected from the enabled features, such as `v1_18`
+    pub version_override: Option<String>,
+    /// Name of the library selected if the dependency is an `any-of` group
+    pub alternative: Option<String>,
+    /// Custom cfg, defined using `cfgs-if-found`, exported because the library has been found
+    pub cfgs: Vec<String>,
     /// From where the library settings have been retrieved
     pub source: Source,
     /// libraries the linker should link on
@@ -1027,45 +2333,14 @@ pub struct Library {
     pub version: String,
     /// library is statically linked
     pub statik: bool,
+    /// `.pc` files the library settings have been retrieved from,
+    /// including the ones of its transitive dependencies
+    pub pc_files: Vec<PathBuf>,
 }
 
 impl Library {
-    fn from_pkg_config(name: &str, l: pkg_config::Library) -> Self {
-        // taken from: https://github.com/rust-lang/pkg-config-rs/blob/54325785816695df031cef3b26b6a9a203bbc01b/src/lib.rs#L502
-        let system_roots = if cfg!(target_os = "macos") {
-            vec![PathBuf::from("/Library"), PathBuf::from("/System")]
-        } else {
-            let sysroot = env::var_os("PKG_CONFIG_SYSROOT_DIR")
-                .or_else(|| env::var_os("SYSROOT"))
-                .map(PathBuf::from);
-
-            if cfg!(target_os = "windows") {
-                if let Some(sysroot) = sysroot {
-                    vec![sysroot]
-                } else {
-                    vec![]
-                }
-            } else {
-                vec![sysroot.unwrap_or_else(|| PathBuf::from("/usr"))]
-            }
-        };
-
-        let is_static_available = |name: &String| -> bool {
-            let libnames = {
-                let mut names = vec![format!("lib{}.a", name)];
-
-                if cfg!(target_os = "windows") {
-                    names.push(format!("{}.lib", name));
-                }
-
-                names
-            };
-
-            l.link_paths.iter().any(|dir| {
-                let library_exists = libnames.iter().any(|libname| dir.join(libname).exists());
-                library_exists && !system_roots.iter().any(|sys| dir.starts_with(sys))
-            })
-        };
+    pub(crate) fn from_pkg_config(name: &str, l: pkg_config::Library, env: &dyn EnvSource) -> Self {
+        let system_roots = system_roots(env);
 
         Self {
             name: name.to_string(),
@@ -1073,7 +2348,10 @@ impl Library {
             libs: l
                 .libs
                 .iter()
-                .map(|lib| InternalLib::new(lib.to_owned(), is_static_available(lib)))
+                .map(|lib| {
+                    let statik = is_static_available(lib, &l.link_paths, &system_roots);
+                    InternalLib::new(lib.to_owned(), statik)
+                })
                 .collect(),
             link_paths: l.link_paths,
             include_paths: l.include_paths,
@@ -1083,10 +2361,16 @@ impl Library {
             defines: l.defines,
             version: l.version,
             statik: false,
+            pc_files: Vec::new(),
+            requested_name: name.to_string(),
+            required_version: String::new(),
+            version_override: None,
+            alternative: None,
+            cfgs: Vec::new(),
         }
     }
 
-    fn from_env_variables(name: &str) -> Self {
+    pub(crate) fn from_env_variables(name: &str) -> Self {
         Self {
             name: name.to_string(),
             source: Source::EnvVariables,
@@ -1099,6 +2383,12 @@ impl Library {
             defines: HashMap::new(),
             version: String::new(),
             statik: false,
+            pc_files: Vec::new(),
+            requested_name: name.to_string(),
+            required_version: String::new(),
+            version_override: None,
+            alternative: None,
+            cfgs: Vec::new(),
         }
     }
 
@@ -1107,6 +2397,7 @@ impl Library {
     /// after having built the lib to return the library information to system-deps.
     ///
     /// This library will be statically linked.
+    /// See [InternalPkgConfig] to use several directories or to relocate the library.
     ///
     /// # Arguments
     ///
@@ -1132,44 +2423,170 @@ impl Library {
     where
         P: AsRef<Path>,
     {
-        // save current PKG_CONFIG_PATH, so we can restore it
-        let old = env::var("PKG_CONFIG_PATH");
-
-        match old {
-            Ok(ref s) => {
-                let mut paths = env::split_paths(s).collect::<Vec<_>>();
-                paths.push(PathBuf::from(pkg_config_dir.as_ref()));
-                let paths = env::join_paths(paths).unwrap();
-                env::set_var("PKG_CONFIG_PATH", paths)
-            }
-            Err(_) => env::set_var("PKG_CONFIG_PATH", pkg_config_dir.as_ref()),
-        }
+        InternalPkgConfig::new()
+            .pc_dir(pkg_config_dir)
+            .probe(lib, version)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Settings used to look for a library which has been built internally,
+/// typically from a `Config::add_build_internal` closure.
+///
+/// The search paths are appended to `PKG_CONFIG_PATH` while `pkg-config` runs. As the
+/// `pkg-config` crate only reads the process environment, it is temporarily modified and restored
+/// once done, holding a lock so libraries can be probed from multiple threads.
+///
+/// ```
+/// # fn build() -> Result<system_deps::Library, system_deps::BuildInternalClosureError> {
+/// // The library has been built with `--prefix=/usr` then installed into `build-dir/install`
+/// system_deps::InternalPkgConfig::new()
+///     .pc_dir("build-dir/install/usr/lib/pkgconfig")
+///     .rewrite_path("/usr", "build-dir/install/usr")
+///     .probe("mylib", "1.2")
+/// # }
+/// ```
+pub struct InternalPkgConfig {
+    pc_dirs: Vec<PathBuf>,
+    variables: Vec<(String, String)>,
+    rewrites: Vec<(PathBuf, PathBuf)>,
+}
+
+impl InternalPkgConfig {
+    /// Create settings without any search directory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look for `.pc` files in `dir`, after the directories listed in `PKG_CONFIG_PATH`.
+    ///
+    /// Directories are searched in the order they have been added.
+    pub fn pc_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.pc_dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Override the `prefix` variable of the `.pc` files, for libraries which have been
+    /// installed in a different location than the one they have been configured with.
+    ///
+    /// Like with `pkg-config --define-variable`, this also applies to the `.pc` files of the
+    /// modules required by the library.
+    pub fn prefix<P: AsRef<Path>>(self, prefix: P) -> Self {
+        let prefix = prefix.as_ref().to_string_lossy().to_string();
+        self.define_variable("prefix", &prefix)
+    }
+
+    /// Override the variable `name` of the `.pc` files with `value`
+    pub fn define_variable(mut self, name: &str, value: &str) -> Self {
+        self.variables.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Replace `from` by `to` at the start of the include, link and framework paths
+    /// of the library, for example to move them into a sysroot.
+    pub fn rewrite_path<P: AsRef<Path>, Q: AsRef<Path>>(mut self, from: P, to: Q) -> Self {
+        self.rewrites
+            .push((from.as_ref().to_path_buf(), to.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Look for `lib` with at least version `version`.
+    ///
+    /// The returned library will be statically linked.
+    pub fn probe(&self, lib: &str, version: &str) -> Result<Library, BuildInternalClosureError> {
+        self.probe_with_env(lib, version, &ProcessEnv)
+    }
+
+    /// Look for `lib` like [InternalPkgConfig::probe], reading the environment variables
+    /// from `env` rather than from the process environment, such as [BuildInternalContext::env].
+    pub fn probe_with_env(
+        &self,
+        lib: &str,
+        version: &str,
+        env: &dyn EnvSource,
+    ) -> Result<Library, BuildInternalClosureError> {
+        Ok(self.probe_range(lib, version.., true, env)?)
+    }
 
-        let pkg_lib = pkg_config::Config::new()
-            .atleast_version(version)
+    // Look for `lib` with a version in `range` using the `pkg-config` crate, which reads the
+    // variables of `env` and looks into `pc_dirs` after the directories of `PKG_CONFIG_PATH`
+    pub(crate) fn probe_range<'a>(
+        &self,
+        lib: &str,
+        range: impl RangeBounds<&'a str>,
+        statik: bool,
+        env: &dyn EnvSource,
+    ) -> Result<Library, pkg_config::Error> {
+        let mut config = pkg_config::Config::new();
+        config
             .print_system_libs(false)
             .cargo_metadata(false)
-            .statik(true)
-            .probe(lib);
+            .range_version(range)
+            .statik(statik);
+        for (name, value) in &self.variables {
+            config.arg(format!("--define-variable={}={}", name, value));
+        }
+
+        let mut l = pc_files::with_process_env(env, lib, &self.pc_dirs, || config.probe(lib))
+            .map_err(|e| pkg_config::Error::Command {
+                command: "pkg-config".to_string(),
+                cause: std::io::Error::new(std::io::ErrorKind::InvalidInput, e),
+            })??;
+
+        for paths in [
+            &mut l.link_paths,
+            &mut l.include_paths,
+            &mut l.framework_paths,
+        ] {
+            for path in paths.iter_mut() {
+                *path = self.rewrite(path);
+            }
+        }
+        let mut library = Library::from_pkg_config(lib, l, env);
+        library.statik = statik;
+        library.pc_files = pc_files::resolve(env, lib, statik, &self.pc_dirs);
+        Ok(library)
+    }
 
-        env::set_var("PKG_CONFIG_PATH", old.unwrap_or_else(|_| "".into()));
+    // Why `lib` has not been found: probe it again without any version constraint to tell
+    // whether it is missing or only has the wrong version
+    pub(crate) fn failure_kind(&self, lib: &str, env: &dyn EnvSource) -> ProbeErrorKind {
+        match self.probe_range(lib, .., false, env) {
+            Ok(_) => ProbeErrorKind::WrongVersion,
+            Err(pkg_config::Error::ProbeFailure { .. })
+            | Err(pkg_config::Error::Failure { .. }) => ProbeErrorKind::NotFound,
+            Err(_) => ProbeErrorKind::Other,
+        }
+    }
 
-        match pkg_lib {
-            Ok(pkg_lib) => {
-                let mut lib = Self::from_pkg_config(lib, pkg_lib);
-                lib.statik = true;
-                Ok(lib)
+    fn rewrite(&self, path: &Path) -> PathBuf {
+        for (from, to) in &self.rewrites {
+            if let Ok(rest) = path.strip_prefix(from) {
+                return to.join(rest);
             }
-            Err(e) => Err(e.into()),
         }
+        path.to_path_buf()
     }
 }
 
-#[derive(Debug)]
-enum EnvVariables {
-    Environment,
-    #[cfg(test)]
-    Mock(HashMap<&'static str, String>),
+struct EnvVariables(Box<dyn EnvSource>);
+
+impl EnvVariables {
+    // Value of a boolean variable, `false` if it is not defined
+    fn enabled(&self, var: &EnvVariable) -> Result<bool, Error> {
+        let value = match self.get(var) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "" | "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(Error::InvalidEnvVariable {
+                name: var.to_string(),
+                value,
+            }),
+        }
+    }
 }
 
 trait EnvVariablesExt<T> {
@@ -1189,11 +2606,7 @@ trait EnvVariablesExt<T> {
 
 impl EnvVariablesExt<&str> for EnvVariables {
     fn get(&self, var: &str) -> Option<String> {
-        match self {
-            EnvVariables::Environment => env::var(var).ok(),
-            #[cfg(test)]
-            EnvVariables::Mock(vars) => vars.get(var).cloned(),
-        }
+        self.0.var(var)
     }
 }
 
@@ -1205,15 +2618,29 @@ impl EnvVariablesExt<&EnvVariable> for EnvVariables {
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum BuildFlag {
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A cargo directive printed by the build script
+pub enum BuildFlag {
+    /// `cargo:include`, exporting the header paths to dependent crates
     Include(String),
+    /// `cargo:rustc-link-search=native`
     SearchNative(String),
+    /// `cargo:rustc-link-search=framework`
     SearchFramework(String),
-    Lib(String, bool), // true if static
+    /// `cargo:rustc-link-lib`, statically linked if the second field is `true`
+    Lib(String, bool),
+    /// `cargo:rustc-link-lib=framework`
     LibFramework(String),
-    RerunIfEnvChanged(EnvVariable),
+    /// `cargo:rerun-if-env-changed`
+    RerunIfEnvChanged(String),
+    /// `cargo:rerun-if-changed`
+    RerunIfChanged(String),
+    /// `cargo:rustc-link-arg`
     LinkArg(Vec<String>),
+    /// `cargo:rustc-cfg`
+    Cfg(String),
+    /// `cargo:rustc-env`, with the name and the value of the variable
+    Env(String, String),
 }
 
 impl fmt::Display for BuildFlag {
@@ -1231,24 +2658,50 @@ impl fmt::Display for BuildFlag {
             }
             BuildFlag::LibFramework(lib) => write!(f, "rustc-link-lib=framework={}", lib),
             BuildFlag::RerunIfEnvChanged(env) => write!(f, "rerun-if-env-changed={}", env),
+            BuildFlag::RerunIfChanged(path) => write!(f, "rerun-if-changed={}", path),
             BuildFlag::LinkArg(ld_option) => {
                 write!(f, "rustc-link-arg=-Wl,{}", ld_option.join(","))
             }
+            BuildFlag::Cfg(cfg) => write!(f, "rustc-cfg={}", cfg),
+            BuildFlag::Env(name, value) => write!(f, "rustc-env={}={}", name, value),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct BuildFlags(Vec<BuildFlag>);
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+/// The cargo directives generated by [Dependencies::build_flags].
+///
+/// Its [Display](fmt::Display) implementation prints them in the format expected by cargo.
+pub struct BuildFlags(Vec<BuildFlag>);
 
 impl BuildFlags {
     fn new() -> Self {
         Self(Vec::new())
     }
 
-    fn add(&mut self, flag: BuildFlag) {
+    /// Add a directive
+    pub fn add(&mut self, flag: BuildFlag) {
         self.0.push(flag);
     }
+
+    /// Iterate over the directives
+    pub fn iter(&self) -> std::slice::Iter<'_, BuildFlag> {
+        self.0.iter()
+    }
+
+    /// Only keep the directives for which `f` returns `true`
+    pub fn retain<F: FnMut(&BuildFlag) -> bool>(&mut self, f: F) {
+        self.0.retain(f);
+    }
+}
+
+impl IntoIterator for BuildFlags {
+    type Item = BuildFlag;
+    type IntoIter = std::vec::IntoIter<BuildFlag>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
 }
 
 impl fmt::Display for BuildFlags {
@@ -1277,19 +2730,14 @@ fn split_string(value: &str) -> Vec<String> {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Default)]
 enum BuildInternal {
     Auto,
     Always,
+    #[default]
     Never,
 }
 
-impl Default for BuildInternal {
-    fn default() -> Self {
-        Self::Never
-    }
-}
-
 impl FromStr for BuildInternal {
     type Err = ParseError;
 
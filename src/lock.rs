@@ -0,0 +1,354 @@
+// Record the resolved system dependencies in system-deps.lock and check them
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use toml::{map::Map, Value};
+
+use crate::{Dependencies, Error, Library};
+
+pub(crate) const LOCKFILE_NAME: &str = "system-deps.lock";
+
+// Lock files older than this are left over by killed build scripts
+const STALE_LOCK: Duration = Duration::from_secs(30);
+
+const HEADER: &str = "# This file is automatically generated by system-deps.\n\
+                      # It is not intended for manual editing.\n\n";
+
+#[derive(Debug, PartialEq)]
+struct LockEntry {
+    name: String,
+    version: String,
+    hash: Option<String>,
+}
+
+impl LockEntry {
+    fn from_library(lib: &Library) -> Self {
+        Self {
+            name: lib.name.clone(),
+            version: lib.version.clone(),
+            hash: hash_pc_files(&lib.pc_files),
+        }
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let table = value.as_table()?;
+        Some(Self {
+            name: table.get("name")?.as_str()?.to_string(),
+            version: table.get("version")?.as_str()?.to_string(),
+            hash: table
+                .get("hash")
+                .and_then(|h| h.as_str())
+                .map(|h| h.to_string()),
+        })
+    }
+
+    fn to_value(&self) -> Value {
+        let mut table = Map::new();
+        table.insert("name".into(), Value::String(self.name.clone()));
+        table.insert("version".into(), Value::String(self.version.clone()));
+        if let Some(hash) = &self.hash {
+            table.insert("hash".into(), Value::String(hash.clone()));
+        }
+        Value::Table(table)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Lockfile {
+    path: PathBuf,
+    packages: Map<String, Value>,
+}
+
+impl Lockfile {
+    /// The lock file lives next to `Cargo.lock`, which is in the workspace root.
+    /// Fallback to the crate directory if there is none.
+    pub(crate) fn path(manifest_dir: &Path) -> PathBuf {
+        let dir = manifest_dir
+            .ancestors()
+            .find(|dir| dir.join("Cargo.lock").exists())
+            .unwrap_or(manifest_dir);
+        dir.join(LOCKFILE_NAME)
+    }
+
+    /// Load the lock file at `path`, starting with an empty one if it does not exist yet.
+    pub(crate) fn load(path: PathBuf) -> Result<Self, Error> {
+        // Wait for build scripts updating the file in parallel
+        let _guard = LockGuard::acquire(&path).map_err(|e| read_error(&path, e))?;
+        let content = match File::open(&path) {
+            Ok(mut file) => {
+                let mut content = String::new();
+                file.read_to_string(&mut content)
+                    .map_err(|e| read_error(&path, e))?;
+                content
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(read_error(&path, e)),
+        };
+
+        Self::parse(path, &content)
+    }
+
+    fn parse(path: PathBuf, content: &str) -> Result<Self, Error> {
+        let packages = content.parse::<toml::Table>().map_err(|e| {
+            Error::InvalidLockfile(format!("{}: error parsing TOML: {}", path.display(), e))
+        })?;
+
+        Ok(Self { path, packages })
+    }
+
+    /// Check that the dependencies of `package` match the ones recorded in the lock file.
+    pub(crate) fn check(&self, package: &str, libraries: &Dependencies) -> Result<(), Error> {
+        let locked = self
+            .packages
+            .get(package)
+            .and_then(|p| p.as_table())
+            .ok_or_else(|| {
+                Error::InvalidLockfile(format!("{}: no entry for {}", self.path.display(), package))
+            })?;
+
+        let mut mismatches = Vec::new();
+
+        for (key, lib) in libraries.iter() {
+            let current = LockEntry::from_library(lib);
+            match locked.get(key).and_then(LockEntry::from_value) {
+                None => mismatches.push(format!("{} is not locked", key)),
+                Some(entry) => {
+                    if entry.name != current.name {
+                        mismatches.push(format!(
+                            "{} resolved to {} but {} is locked",
+                            key, current.name, entry.name
+                        ));
+                    }
+                    if entry.version != current.version {
+                        mismatches.push(format!(
+                            "{} has version {} but {} is locked",
+                            key, current.version, entry.version
+                        ));
+                    }
+                    if entry.hash != current.hash {
+                        mismatches.push(format!("{}: .pc files have been modified", key));
+                    }
+                }
+            }
+        }
+
+        for key in locked.keys() {
+            if libraries.get_by_name(key).is_none() {
+                mismatches.push(format!("{} is locked but has not been found", key));
+            }
+        }
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::LockfileMismatch(format!(
+                "{}: {}",
+                self.path.display(),
+                mismatches.join(", ")
+            )))
+        }
+    }
+
+    /// Replace the entries of `package` in the lock file at `path` with its currently
+    /// resolved dependencies, keeping the entries of the other crates.
+    ///
+    /// The file is locked while being updated so build scripts running in parallel
+    /// do not overwrite each other's entries.
+    pub(crate) fn update(
+        path: PathBuf,
+        package: &str,
+        libraries: &Dependencies,
+    ) -> Result<Self, Error> {
+        // The lock is released when the guard is dropped
+        let _guard = LockGuard::acquire(&path).map_err(|e| read_error(&path, e))?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| read_error(&path, e))?;
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .map_err(|e| read_error(&path, e))?;
+        let mut lockfile = Self::parse(path, &content)?;
+
+        let entries = libraries
+            .iter()
+            .into_iter()
+            .map(|(key, lib)| (key.to_string(), LockEntry::from_library(lib).to_value()))
+            .collect();
+        lockfile
+            .packages
+            .insert(package.to_string(), Value::Table(entries));
+
+        let content = format!("{}{}", HEADER, lockfile.packages);
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| file.write_all(content.as_bytes()))
+            .map_err(|e| {
+                Error::FailToWrite(format!("error writing {}", lockfile.path.display()), e)
+            })?;
+
+        Ok(lockfile)
+    }
+
+    pub(crate) fn file_path(&self) -> &Path {
+        &self.path
+    }
+}
+
+// Exclusive lock on a lock file, held by creating `<lock file>.lck` next to it.
+// `File::lock` is not used as it requires a recent Rust version.
+struct LockGuard {
+    path: PathBuf,
+}
+
+impl LockGuard {
+    fn acquire(lockfile: &Path) -> io::Result<Self> {
+        let mut path = lockfile.as_os_str().to_owned();
+        path.push(".lck");
+        let path = PathBuf::from(path);
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let age = fs::metadata(&path)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+                    if matches!(age, Some(age) if age > STALE_LOCK) {
+                        let _ = fs::remove_file(&path);
+                    } else {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn read_error(path: &Path, e: io::Error) -> Error {
+    Error::FailToRead(format!("error reading {}", path.display()), e)
+}
+
+// Stable hash of the content of the .pc files, so it can be compared across builds.
+fn hash_pc_files(pc_files: &[PathBuf]) -> Option<String> {
+    if pc_files.is_empty() {
+        return None;
+    }
+
+    let mut hash = FNV_OFFSET_BASIS;
+    for pc_file in pc_files {
+        // Unreadable files are hashed as empty ones
+        let content = fs::read(pc_file).unwrap_or_default();
+        hash = fnv1a(hash, &content);
+    }
+
+    Some(format!("{:016x}", hash))
+}
+
+pub(crate) const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+pub(crate) fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn library(name: &str, version: &str) -> Library {
+        let mut lib = Library::from_env_variables(name);
+        lib.version = version.into();
+        lib
+    }
+
+    #[test]
+    fn update() {
+        let dir = env::temp_dir().join(format!("system-deps-lock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(LOCKFILE_NAME);
+        let _ = fs::remove_file(&path);
+
+        let mut libraries = Dependencies::default();
+        libraries.add("testlib", library("testlib", "1.2.3"));
+
+        Lockfile::update(path.clone(), "foo-sys", &libraries).unwrap();
+        Lockfile::update(path.clone(), "bar-sys", &libraries).unwrap();
+
+        let lockfile = Lockfile::load(path.clone()).unwrap();
+        lockfile.check("foo-sys", &libraries).unwrap();
+        lockfile.check("bar-sys", &libraries).unwrap();
+
+        // A newer version is now installed
+        let mut libraries = Dependencies::default();
+        libraries.add("testlib", library("testlib", "1.2.4"));
+        assert!(matches!(
+            lockfile.check("foo-sys", &libraries),
+            Err(Error::LockfileMismatch(_))
+        ));
+
+        // Updating one crate keeps the others
+        Lockfile::update(path.clone(), "foo-sys", &libraries).unwrap();
+
+        let lockfile = Lockfile::load(path.clone()).unwrap();
+        lockfile.check("foo-sys", &libraries).unwrap();
+        assert!(lockfile.check("bar-sys", &libraries).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        let dir = env::temp_dir().join(format!(
+            "system-deps-lock-concurrent-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(LOCKFILE_NAME);
+        let _ = fs::remove_file(&path);
+
+        let mut libraries = Dependencies::default();
+        libraries.add("testlib", library("testlib", "1.2.3"));
+
+        // Build scripts of several crates updating the same lock file in parallel
+        let packages = (0..8)
+            .map(|i| format!("crate-{}-sys", i))
+            .collect::<Vec<_>>();
+        std::thread::scope(|s| {
+            for package in &packages {
+                let (path, libraries) = (&path, &libraries);
+                s.spawn(move || Lockfile::update(path.clone(), package, libraries).unwrap());
+            }
+        });
+
+        let lockfile = Lockfile::load(path).unwrap();
+        for package in &packages {
+            lockfile.check(package, &libraries).unwrap();
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
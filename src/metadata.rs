@@ -1,12 +1,22 @@
 // Parse system-deps metadata from Cargo.toml
 
-use std::{fmt, fs, io::Read, path::Path};
+use std::{
+    fmt, fs,
+    io::Read,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
 use toml::{map::Map, Value};
+use toml_edit::{ImDocument, Item, Table};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Default)]
 pub(crate) struct MetaData {
     pub(crate) deps: Vec<Dependency>,
+    /// The workspace manifest the dependencies have been inherited from, if any
+    pub(crate) workspace_manifest: Option<PathBuf>,
+    /// The features of the package, including the implicit ones of its optional dependencies
+    pub(crate) features: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -19,6 +29,11 @@ pub(crate) struct Dependency {
     pub(crate) optional: bool,
     pub(crate) cfg: Option<cfg_expr::Expression>,
     pub(crate) version_overrides: Vec<VersionOverride>,
+    pub(crate) cfg_overrides: Vec<CfgOverride>,
+    pub(crate) alternatives: Vec<Alternative>,
+    pub(crate) cfgs_if_found: Vec<String>,
+    pub(crate) workspace: bool,
+    pub(crate) requires: Vec<String>,
 }
 
 impl Dependency {
@@ -32,6 +47,55 @@ impl Dependency {
     pub(crate) fn lib_name(&self) -> &str {
         self.name.as_ref().unwrap_or(&self.key)
     }
+
+    pub(crate) fn from_spec(
+        key: &str,
+        spec: &crate::DependencySpec,
+    ) -> Result<Self, MetadataError> {
+        if let Some(version) = &spec.version {
+            if !validate_version(version) {
+                return Err(MetadataError::InvalidVersion {
+                    key: key.to_string(),
+                    version: version.clone(),
+                });
+            }
+        }
+
+        let cfg = spec
+            .cfg
+            .as_deref()
+            .map(cfg_expr::Expression::parse)
+            .transpose()
+            .map_err(|e| MetadataError::cfg_expr(key, e))?;
+
+        Ok(Self {
+            key: key.to_string(),
+            version: spec.version.clone(),
+            name: spec.name.clone(),
+            fallback_names: spec.fallback_names.clone(),
+            feature: spec.feature.clone(),
+            optional: spec.optional,
+            cfg,
+            requires: spec.requires.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// Replace the settings of the dependency by the ones defined in `o`.
+    pub(crate) fn merge_cfg_override(&mut self, o: &CfgOverride) {
+        if let Some(version) = &o.version {
+            self.version = Some(version.clone());
+        }
+        if let Some(name) = &o.name {
+            self.name = Some(name.clone());
+        }
+        if let Some(fallback_names) = &o.fallback_names {
+            self.fallback_names = Some(fallback_names.clone());
+        }
+        if let Some(optional) = o.optional {
+            self.optional = optional;
+        }
+    }
 }
 
 impl Default for Dependency {
@@ -45,58 +109,193 @@ impl Default for Dependency {
             optional: false,
             cfg: None,
             version_overrides: Vec::new(),
+            cfg_overrides: Vec::new(),
+            alternatives: Vec::new(),
+            cfgs_if_found: Vec::new(),
+            workspace: false,
+            requires: Vec::new(),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum VersionOverrideBuilderError {
-    MissingVersionField,
+/// Error in the system-deps metadata of a manifest, reported by [crate::Error::InvalidManifest].
+///
+/// The `key` fields contain the dotted path of the offending value in the manifest,
+/// such as `package.metadata.system-deps.testlib`, or the key of the dependency
+/// if it has been declared using [crate::Config::add_dependency].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MetadataError {
+    /// A required key is not defined
+    MissingKey {
+        /// Path of the missing key
+        key: String,
+    },
+    /// The value is not a table
+    NotATable {
+        /// Path of the value
+        key: String,
+    },
+    /// The value is neither a version string nor a table
+    NotStringOrTable {
+        /// Path of the value
+        key: String,
+    },
+    /// The value is not a string
+    NotString {
+        /// Path of the value
+        key: String,
+    },
+    /// The `cfg()` expression cannot be parsed
+    CfgExpr {
+        /// Path of the table defined for the expression
+        key: String,
+        /// Description of the parsing error
+        message: String,
+        /// The parsing error, also returned by [std::error::Error::source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The manifest is not valid TOML
+    Toml {
+        /// Description of the parsing error
+        message: String,
+        /// Byte range of the error in the manifest, if known
+        span: Option<std::ops::Range<usize>>,
+        /// The parsing error, also returned by [std::error::Error::source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// A version setting is not a valid version range, or has an unexpected type
+    UnexpectedVersionSetting {
+        /// Path of the table containing the setting
+        key: String,
+        /// Name of the setting
+        setting: String,
+        /// TOML type of the value
+        value_type: String,
+    },
+    /// A key is not supported in this table, or its value has an unexpected type
+    UnexpectedKey {
+        /// Path of the table containing the key
+        key: String,
+        /// Name of the unexpected key
+        setting: String,
+        /// TOML type of the value
+        value_type: String,
+    },
+    /// A version override does not define the version to require
+    MissingVersion {
+        /// Path of the override
+        key: String,
+    },
+    /// A key cannot be used along with another key of the same table
+    ConflictingKeys {
+        /// Path of the table containing the keys
+        key: String,
+        /// Name of the key which cannot be used
+        setting: String,
+        /// Name of the key it conflicts with
+        other: String,
+    },
+    /// The version of a dependency is not a valid version range
+    InvalidVersion {
+        /// Path of the dependency
+        key: String,
+        /// The invalid version
+        version: String,
+    },
+    /// A dependency requires a dependency which is not defined
+    UnknownRequirement {
+        /// Path of the dependency
+        key: String,
+        /// Key of the required dependency
+        required: String,
+    },
+    /// Dependencies require each other
+    RequirementCycle {
+        /// Keys of the dependencies part of the cycle
+        keys: Vec<String>,
+    },
 }
 
-impl fmt::Display for VersionOverrideBuilderError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
-            Self::MissingVersionField => write!(f, "missing version field"),
+impl MetadataError {
+    // Path of the value the error is about, used to locate it in the manifest
+    fn path(&self) -> Option<String> {
+        match self {
+            Self::MissingKey { key }
+            | Self::NotATable { key }
+            | Self::NotStringOrTable { key }
+            | Self::NotString { key }
+            | Self::CfgExpr { key, .. }
+            | Self::MissingVersion { key }
+            | Self::InvalidVersion { key, .. } => Some(key.clone()),
+            Self::UnexpectedVersionSetting { key, setting, .. }
+            | Self::UnexpectedKey { key, setting, .. }
+            | Self::ConflictingKeys { key, setting, .. } => Some(format!("{}.{}", key, setting)),
+            Self::UnknownRequirement { key, .. } => Some(format!("{}.requires", key)),
+            Self::Toml { .. } | Self::RequirementCycle { .. } => None,
         }
     }
-}
-
-impl std::error::Error for VersionOverrideBuilderError {}
 
-#[derive(Debug, PartialEq)]
-enum MetadataError {
-    MissingKey(String),
-    NotATable(String),
-    NestedCfg(String),
-    NotStringOrTable(String),
-    NotString(String),
-    CfgExpr(cfg_expr::ParseError),
-    Toml(toml::de::Error),
-    UnexpectedVersionSetting(String, String, String),
-    UnexpectedKey(String, String, String),
-    VersionOverrideBuilder(VersionOverrideBuilderError),
+    fn cfg_expr(key: &str, error: cfg_expr::ParseError) -> Self {
+        Self::CfgExpr {
+            key: key.to_string(),
+            message: error.to_string(),
+            source: Box::new(error),
+        }
+    }
 }
 
 impl fmt::Display for MetadataError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::MissingKey(k) => write!(f, "missing key `{}`", k),
-            Self::NotATable(k) => write!(f, "`{}` is not a table", k),
-            Self::NestedCfg(k) => write!(f, "`{}`: cfg() cannot be nested", k),
-            Self::NotString(k) => write!(f, "`{}`: not a string", k),
-            Self::NotStringOrTable(k) => write!(f, "`{}`: not a string or a table", k),
-            Self::CfgExpr(e) => write!(f, "{}", e),
-            Self::Toml(e) => write!(f, "error parsing TOML: {}", e),
-            Self::UnexpectedVersionSetting(n, k, t) => {
+            Self::MissingKey { key } => write!(f, "missing key `{}`", key),
+            Self::NotATable { key } => write!(f, "`{}` is not a table", key),
+            Self::NotString { key } => write!(f, "`{}`: not a string", key),
+            Self::NotStringOrTable { key } => write!(f, "`{}`: not a string or a table", key),
+            Self::CfgExpr { message, .. } => write!(f, "{}", message),
+            Self::Toml { message, .. } => write!(f, "error parsing TOML: {}", message),
+            Self::UnexpectedVersionSetting {
+                key,
+                setting,
+                value_type,
+            } => {
                 write!(
                     f,
                     "{}: unexpected version settings key: {} type: {}",
-                    n, k, t
+                    key, setting, value_type
                 )
             }
-            Self::UnexpectedKey(n, k, t) => write!(f, "{}: unexpected key {} type {}", n, k, t),
-            Self::VersionOverrideBuilder(e) => write!(f, "{}", e),
+            Self::UnexpectedKey {
+                key,
+                setting,
+                value_type,
+            } => write!(f, "{}: unexpected key {} type {}", key, setting, value_type),
+            Self::MissingVersion { key } => write!(f, "{}: missing version field", key),
+            Self::ConflictingKeys {
+                key,
+                setting,
+                other,
+            } => write!(
+                f,
+                "{}: {} cannot be used along with {}",
+                key, setting, other
+            ),
+            Self::InvalidVersion { key, version } => {
+                write!(f, "{}: invalid version range `{}`", key, version)
+            }
+            Self::UnknownRequirement { key, required } => write!(
+                f,
+                "{}: required dependency `{}` is not defined",
+                key, required
+            ),
+            Self::RequirementCycle { keys } => {
+                let keys = keys
+                    .iter()
+                    .map(|k| format!("`{}`", k))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "dependency cycle between {}", keys)
+            }
         }
     }
 }
@@ -104,29 +303,334 @@ impl fmt::Display for MetadataError {
 impl std::error::Error for MetadataError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Self::CfgExpr(e) => Some(e),
-            Self::Toml(e) => Some(e),
-            Self::VersionOverrideBuilder(e) => Some(e),
+            Self::CfgExpr { source, .. } | Self::Toml { source, .. } => Some(source.as_ref()),
             _ => None,
         }
     }
 }
 
-impl From<cfg_expr::ParseError> for MetadataError {
-    fn from(err: cfg_expr::ParseError) -> Self {
-        Self::CfgExpr(err)
+impl From<toml_edit::TomlError> for MetadataError {
+    fn from(error: toml_edit::TomlError) -> Self {
+        Self::Toml {
+            message: error.message().to_string(),
+            span: error.span(),
+            source: Box::new(error),
+        }
     }
 }
 
-impl From<toml::de::Error> for MetadataError {
-    fn from(err: toml::de::Error) -> Self {
-        Self::Toml(err)
+impl From<toml_edit::de::Error> for MetadataError {
+    fn from(error: toml_edit::de::Error) -> Self {
+        Self::Toml {
+            message: error.message().to_string(),
+            span: error.span(),
+            source: Box::new(error),
+        }
     }
 }
 
-impl From<VersionOverrideBuilderError> for MetadataError {
-    fn from(err: VersionOverrideBuilderError) -> Self {
-        Self::VersionOverrideBuilder(err)
+/// Location of a [MetadataError] in the manifest, reported by [crate::Error::InvalidManifest]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataLocation {
+    /// Line of the offending value, starting from 1
+    pub line: usize,
+    /// Column of the offending value, starting from 1
+    pub column: usize,
+    /// The offending line, with carets underlining the value
+    pub snippet: String,
+}
+
+impl MetadataLocation {
+    // Location of `span` in `manifest`
+    fn from_span(manifest: &str, span: Range<usize>) -> Self {
+        let start = span.start.min(manifest.len());
+        let line_start = manifest[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = manifest[start..]
+            .find('\n')
+            .map_or(manifest.len(), |i| start + i);
+        let text = manifest[line_start..line_end].trim_end_matches('\r');
+        let end = span.end.clamp(start, line_start + text.len());
+
+        let line = manifest[..start].matches('\n').count() + 1;
+        let column = manifest[line_start..start].chars().count() + 1;
+        let width = manifest[start..end].chars().count().max(1);
+
+        let number = line.to_string();
+        let margin = " ".repeat(number.len());
+        let snippet = format!(
+            "{} |\n{} | {}\n{} | {}{}",
+            margin,
+            number,
+            text,
+            margin,
+            " ".repeat(column - 1),
+            "^".repeat(width)
+        );
+
+        Self {
+            line,
+            column,
+            snippet,
+        }
+    }
+}
+
+/// A manifest parsed once, keeping the location of its values to report errors
+pub(crate) struct Manifest {
+    path: PathBuf,
+    document: ImDocument<String>,
+}
+
+impl Manifest {
+    /// Read and parse the manifest at `path`
+    pub(crate) fn read(path: &Path) -> Result<Self, crate::Error> {
+        let content = read_manifest(path)?;
+        Self::parse(path, content).map_err(|(content, error)| {
+            let location = match &error {
+                MetadataError::Toml {
+                    span: Some(span), ..
+                } => Some(MetadataLocation::from_span(&content, span.clone())),
+                _ => None,
+            };
+            crate::Error::InvalidManifest {
+                path: path.to_path_buf(),
+                error,
+                location,
+            }
+        })
+    }
+
+    // Parse `content`, returning it along with the error if it is not valid TOML
+    fn parse(path: &Path, content: String) -> Result<Self, (String, MetadataError)> {
+        match ImDocument::parse(content.clone()) {
+            Ok(document) => Ok(Self {
+                path: path.to_path_buf(),
+                document,
+            }),
+            Err(e) => Err((content, e.into())),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn value(&self) -> Result<Value, MetadataError> {
+        Ok(toml_edit::de::from_document(self.document.clone())?)
+    }
+
+    /// Error reported for `error` in this manifest, located if possible
+    pub(crate) fn error(&self, error: MetadataError) -> crate::Error {
+        let span = match &error {
+            MetadataError::Toml { span, .. } => span.clone(),
+            _ => error
+                .path()
+                .and_then(|path| find_span(self.document.as_table(), &path)),
+        };
+        crate::Error::InvalidManifest {
+            path: self.path.clone(),
+            location: span.map(|span| MetadataLocation::from_span(self.document.raw(), span)),
+            error,
+        }
+    }
+}
+
+fn read_manifest(path: &Path) -> Result<String, crate::Error> {
+    let mut manifest = fs::File::open(path)
+        .map_err(|e| crate::Error::FailToRead(format!("error opening {}", path.display()), e))?;
+
+    let mut manifest_str = String::new();
+    manifest
+        .read_to_string(&mut manifest_str)
+        .map_err(|e| crate::Error::FailToRead(format!("error reading {}", path.display()), e))?;
+
+    Ok(manifest_str)
+}
+
+// A value of the manifest, whose location is known unless it is an implicit table such as
+// `package` in `[package.metadata.system-deps]`
+#[derive(Clone, Copy)]
+enum SpanNode<'a> {
+    Item(&'a Item),
+    Value(&'a toml_edit::Value),
+    Table(&'a Table),
+}
+
+impl<'a> SpanNode<'a> {
+    fn span(&self) -> Option<Range<usize>> {
+        match self {
+            Self::Item(item) => item.span(),
+            Self::Value(value) => value.span(),
+            Self::Table(table) => table.span(),
+        }
+    }
+
+    // Return the child of the node at the start of `path` and the length of its key.
+    // Keys are matched against the ones of the manifest as they may contain dots, such as `cfg()` ones.
+    fn child(&self, path: &str) -> Option<(usize, SpanNode<'a>)> {
+        fn key_matches(path: &str, key: &str) -> bool {
+            match path.strip_prefix(key) {
+                Some(rest) => rest.is_empty() || rest.starts_with('.') || rest.starts_with('['),
+                None => false,
+            }
+        }
+
+        fn index<'a>(
+            path: &str,
+            mut values: impl Iterator<Item = SpanNode<'a>>,
+        ) -> Option<(usize, SpanNode<'a>)> {
+            let (i, _) = path.strip_prefix('[')?.split_once(']')?;
+            Some((i.len() + 2, values.nth(i.parse::<usize>().ok()?)?))
+        }
+
+        use toml_edit::Value::{Array, InlineTable};
+
+        let entries: Vec<(&str, SpanNode<'a>)> = match *self {
+            Self::Table(table) | Self::Item(Item::Table(table)) => table
+                .iter()
+                .map(|(key, item)| (key, Self::Item(item)))
+                .collect(),
+            Self::Value(InlineTable(table)) | Self::Item(Item::Value(InlineTable(table))) => table
+                .iter()
+                .map(|(key, value)| (key, Self::Value(value)))
+                .collect(),
+            Self::Value(Array(array)) | Self::Item(Item::Value(Array(array))) => {
+                return index(path, array.iter().map(Self::Value))
+            }
+            Self::Item(Item::ArrayOfTables(array)) => {
+                return index(path, array.iter().map(Self::Table))
+            }
+            _ => return None,
+        };
+
+        entries
+            .into_iter()
+            .filter(|(key, _)| key_matches(path, key))
+            .max_by_key(|(key, _)| key.len())
+            .map(|(key, node)| (key.len(), node))
+    }
+}
+
+// Return the location of the value at `path` in `document`, or of its closest parent having one
+fn find_span(document: &Table, path: &str) -> Option<Range<usize>> {
+    let mut node = SpanNode::Table(document);
+    let mut rest = path;
+    let mut span = None;
+
+    while let Some((len, child)) = node.child(rest) {
+        rest = &rest[len..];
+        rest = rest.strip_prefix('.').unwrap_or(rest);
+        node = child;
+        span = child.span().or(span);
+    }
+
+    span
+}
+
+/// A suspicious declaration in the system-deps metadata, which is not an error
+/// but is likely to be a mistake.
+///
+/// They are reported by [crate::Config::check_metadata] and [crate::Dependencies::warnings].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MetadataWarning {
+    /// The `feature` of a dependency is not declared by the package
+    UndeclaredFeature {
+        /// Key of the dependency
+        key: String,
+        /// Name of the feature
+        feature: String,
+    },
+    /// The feature of a version override is not declared by the package
+    UndeclaredOverrideFeature {
+        /// Key of the dependency
+        key: String,
+        /// Name of the override feature
+        feature: String,
+    },
+    /// A `v*` override requires a lower version than the override for a previous version
+    DecreasingOverrideVersion {
+        /// Key of the dependency
+        key: String,
+        /// Name of the override
+        feature: String,
+        /// Version required by the override
+        version: String,
+        /// Name of the override for the previous version
+        previous_feature: String,
+        /// Version required by the override for the previous version
+        previous_version: String,
+    },
+    /// The upper bound of a version range is not higher than its lower bound
+    EmptyVersionRange {
+        /// Key of the dependency
+        key: String,
+        /// The version range
+        version: String,
+    },
+    /// Several keys look for the same library
+    DuplicateName {
+        /// Name of the library
+        name: String,
+        /// Keys of the dependencies looking for it
+        keys: Vec<String>,
+    },
+    /// The `fallback-names` of a dependency contain its `name`
+    DuplicateFallbackName {
+        /// Key of the dependency
+        key: String,
+        /// Name of the library
+        name: String,
+    },
+}
+
+impl fmt::Display for MetadataWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UndeclaredFeature { key, feature } => {
+                write!(
+                    f,
+                    "{}: feature `{}` is not declared in [features]",
+                    key, feature
+                )
+            }
+            Self::UndeclaredOverrideFeature { key, feature } => write!(
+                f,
+                "{}: override `{}` does not match any feature declared in [features]",
+                key, feature
+            ),
+            Self::DecreasingOverrideVersion {
+                key,
+                feature,
+                version,
+                previous_feature,
+                previous_version,
+            } => write!(
+                f,
+                "{}: override `{}` requires version `{}`, lower than `{}` required by `{}`",
+                key, feature, version, previous_version, previous_feature
+            ),
+            Self::EmptyVersionRange { key, version } => {
+                write!(
+                    f,
+                    "{}: version range `{}` does not match any version",
+                    key, version
+                )
+            }
+            Self::DuplicateName { name, keys } => write!(
+                f,
+                "library `{}` is looked for by several keys: {}",
+                name,
+                keys.join(", ")
+            ),
+            Self::DuplicateFallbackName { key, name } => {
+                write!(
+                    f,
+                    "{}: fallback-names contain the library name `{}`",
+                    key, name
+                )
+            }
+        }
     }
 }
 
@@ -139,6 +643,25 @@ pub(crate) struct VersionOverride {
     pub(crate) optional: Option<bool>,
 }
 
+/// Settings of a dependency only applied if its `cfg()` expression matches the target
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CfgOverride {
+    pub(crate) cfg: cfg_expr::Expression,
+    pub(crate) version: Option<String>,
+    pub(crate) name: Option<String>,
+    pub(crate) fallback_names: Option<Vec<String>>,
+    pub(crate) optional: Option<bool>,
+}
+
+/// One of the libraries of an `any-of` group
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Alternative {
+    pub(crate) name: String,
+    pub(crate) version: Option<String>,
+    pub(crate) feature: Option<String>,
+    pub(crate) fallback_names: Option<Vec<String>>,
+}
+
 struct VersionOverrideBuilder {
     version_id: String,
     version: Option<String>,
@@ -158,10 +681,10 @@ impl VersionOverrideBuilder {
         }
     }
 
-    fn build(self) -> Result<VersionOverride, VersionOverrideBuilderError> {
-        let version = self
-            .version
-            .ok_or(VersionOverrideBuilderError::MissingVersionField)?;
+    fn build(self, key: &str) -> Result<VersionOverride, MetadataError> {
+        let version = self.version.ok_or_else(|| MetadataError::MissingVersion {
+            key: key.to_string(),
+        })?;
 
         Ok(VersionOverride {
             key: self.version_id,
@@ -174,61 +697,389 @@ impl VersionOverrideBuilder {
 }
 
 impl MetaData {
-    pub(crate) fn from_file(path: &Path) -> Result<Self, crate::Error> {
-        let mut manifest = fs::File::open(path).map_err(|e| {
-            crate::Error::FailToRead(format!("error opening {}", path.display()), e)
-        })?;
+    /// Parse the `package.metadata.$table` section of `manifest`.
+    /// If `required` is `false`, a manifest without this section is considered as having no dependency.
+    ///
+    /// Dependencies defined using `workspace = true` are inherited from the
+    /// `workspace.metadata.$table` section of the workspace root manifest.
+    pub(crate) fn from_manifest(
+        manifest: &Manifest,
+        table: &str,
+        required: bool,
+    ) -> Result<Self, crate::Error> {
+        let mut metadata = manifest
+            .value()
+            .and_then(|value| Self::from_value(&value, "package", table, required))
+            .map_err(|e| manifest.error(e))?;
+
+        if metadata.deps.iter().any(|dep| dep.workspace) {
+            let workspace = Self::find_workspace(manifest.path())?;
+            let workspace_metadata = workspace
+                .value()
+                .and_then(|value| Self::from_value(&value, "workspace", table, true))
+                .map_err(|e| workspace.error(e))?;
+
+            metadata
+                .inherit(&workspace_metadata, table)
+                .map_err(|e| workspace.error(e))?;
+            metadata.workspace_manifest = Some(workspace.path);
+        }
 
-        let mut manifest_str = String::new();
-        manifest.read_to_string(&mut manifest_str).map_err(|e| {
-            crate::Error::FailToRead(format!("error reading {}", path.display()), e)
-        })?;
+        Ok(metadata)
+    }
 
-        Self::from_str(manifest_str)
-            .map_err(|e| crate::Error::InvalidMetadata(format!("{}: {}", path.display(), e)))
+    // Look for the manifest defining the workspace the same way cargo does:
+    // the closest one, starting from the crate itself, having a `[workspace]` section.
+    fn find_workspace(path: &Path) -> Result<Manifest, crate::Error> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        for dir in dir.ancestors() {
+            let candidate = dir.join("Cargo.toml");
+            if !candidate.exists() {
+                continue;
+            }
+
+            // Invalid manifests are skipped
+            let manifest = read_manifest(&candidate)?;
+            if let Ok(manifest) = Manifest::parse(&candidate, manifest) {
+                if manifest.document.contains_key("workspace") {
+                    return Ok(manifest);
+                }
+            }
+        }
+
+        Err(crate::Error::invalid_metadata(
+            None,
+            format!(
+                "{}: dependencies are inherited from the workspace but no workspace has been found",
+                path.display()
+            ),
+        ))
     }
 
-    fn from_str(manifest_str: String) -> Result<Self, MetadataError> {
-        let toml = manifest_str.parse::<toml::Value>()?;
-        let key = "package.metadata.system-deps";
-        let meta = toml
-            .get("package")
+    fn from_value(
+        toml: &Value,
+        section: &str,
+        table: &str,
+        required: bool,
+    ) -> Result<Self, MetadataError> {
+        let key = format!("{}.metadata.{}", section, table);
+        let meta = match toml
+            .get(section)
             .and_then(|v| v.get("metadata"))
-            .and_then(|v| v.get("system-deps"))
-            .ok_or_else(|| MetadataError::MissingKey(key.to_owned()))?;
+            .and_then(|v| v.get(table))
+        {
+            Some(meta) => meta,
+            None if !required => return Ok(MetaData::default()),
+            None => return Err(MetadataError::MissingKey { key }),
+        };
+
+        let deps = Self::parse_deps_table(meta, &key, None)?;
+        let features = if section == "package" {
+            Self::package_features(toml)
+        } else {
+            Vec::new()
+        };
+
+        Ok(MetaData {
+            deps,
+            features,
+            ..Default::default()
+        })
+    }
+
+    // The features declared in `[features]` and the optional dependencies
+    // not referenced using the `dep:` syntax, which are implicit features.
+    fn package_features(toml: &Value) -> Vec<String> {
+        let declared = toml.get("features").and_then(|f| f.as_table());
+        let mut features = declared
+            .map(|f| f.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let uses_dep_syntax = |dep: &str| {
+            declared
+                .into_iter()
+                .flat_map(|f| f.values())
+                .filter_map(|v| v.as_array())
+                .flatten()
+                .any(|v| v.as_str() == Some(&format!("dep:{}", dep)))
+        };
+
+        let targets = toml
+            .get("target")
+            .and_then(|t| t.as_table())
+            .into_iter()
+            .flat_map(|t| t.values());
+        for table in std::iter::once(toml).chain(targets) {
+            for section in ["dependencies", "build-dependencies"] {
+                let deps = table.get(section).and_then(|d| d.as_table());
+                for (dep, spec) in deps.into_iter().flatten() {
+                    let optional = spec.get("optional").and_then(|o| o.as_bool());
+                    if optional == Some(true) && !uses_dep_syntax(dep) && !features.contains(dep) {
+                        features.push(dep.clone());
+                    }
+                }
+            }
+        }
+
+        features
+    }
+
+    /// Look for suspicious declarations which are not errors, such as features which
+    /// are not declared by the package or version ranges matching no version.
+    pub(crate) fn lint(&self) -> Vec<MetadataWarning> {
+        let mut warnings = Vec::new();
+        let has_feature = |f: &str| self.features.iter().any(|feature| feature == f);
+
+        for dep in self.deps.iter() {
+            let features = dep
+                .feature
+                .iter()
+                .chain(dep.alternatives.iter().filter_map(|a| a.feature.as_ref()));
+            for feature in features {
+                if !has_feature(feature) {
+                    warnings.push(MetadataWarning::UndeclaredFeature {
+                        key: dep.key.clone(),
+                        feature: feature.clone(),
+                    });
+                }
+            }
+
+            for o in dep.version_overrides.iter() {
+                if !has_feature(&o.key) {
+                    warnings.push(MetadataWarning::UndeclaredOverrideFeature {
+                        key: dep.key.clone(),
+                        feature: o.key.clone(),
+                    });
+                }
+            }
+
+            warnings.extend(Self::lint_override_order(dep));
+
+            let versions = dep
+                .version
+                .iter()
+                .chain(dep.version_overrides.iter().map(|o| &o.version))
+                .chain(dep.cfg_overrides.iter().filter_map(|o| o.version.as_ref()))
+                .chain(dep.alternatives.iter().filter_map(|a| a.version.as_ref()));
+            for version in versions {
+                let (min, max) = version_bounds(version);
+                // Versions which cannot be compared are not reported
+                let empty = match max {
+                    Some(max) => matches!(
+                        compare_versions(min, max),
+                        Ok(std::cmp::Ordering::Greater | std::cmp::Ordering::Equal)
+                    ),
+                    None => false,
+                };
+                if empty {
+                    warnings.push(MetadataWarning::EmptyVersionRange {
+                        key: dep.key.clone(),
+                        version: version.clone(),
+                    });
+                }
+            }
+
+            let names = std::iter::once((dep.lib_name(), dep.fallback_names.as_ref())).chain(
+                dep.version_overrides.iter().map(|o| {
+                    (
+                        o.name.as_deref().unwrap_or(dep.lib_name()),
+                        o.fallback_names.as_ref().or(dep.fallback_names.as_ref()),
+                    )
+                }),
+            );
+            for (name, fallback_names) in names {
+                if matches!(fallback_names, Some(f) if f.iter().any(|f| f == name)) {
+                    let warning = MetadataWarning::DuplicateFallbackName {
+                        key: dep.key.clone(),
+                        name: name.into(),
+                    };
+                    if !warnings.contains(&warning) {
+                        warnings.push(warning);
+                    }
+                }
+            }
+        }
+
+        // Several keys looking for the same library, keys being repeated for each `cfg()`
+        let mut names: Vec<(&str, Vec<&str>)> = Vec::new();
+        for dep in self.deps.iter() {
+            match names.iter_mut().find(|(name, _)| *name == dep.lib_name()) {
+                Some((_, keys)) if !keys.contains(&dep.key.as_str()) => keys.push(&dep.key),
+                Some(_) => {}
+                None => names.push((dep.lib_name(), vec![&dep.key])),
+            }
+        }
+        for (name, keys) in names {
+            if keys.len() > 1 {
+                warnings.push(MetadataWarning::DuplicateName {
+                    name: name.into(),
+                    keys: keys.into_iter().map(String::from).collect(),
+                });
+            }
+        }
+
+        warnings
+    }
+
+    // The `v*` overrides, sorted by the version in their name, should require increasing versions
+    fn lint_override_order(dep: &Dependency) -> Option<MetadataWarning> {
+        let mut overrides = dep
+            .version_overrides
+            .iter()
+            .filter(|o| is_version_feature(&o.key))
+            .map(|o| (o.key[1..].replace('_', "."), o))
+            .collect::<Vec<_>>();
+        let mut invalid = false;
+        overrides.sort_by(|(a, _), (b, _)| {
+            compare_versions(a, b).unwrap_or_else(|_| {
+                invalid = true;
+                std::cmp::Ordering::Equal
+            })
+        });
+        if invalid {
+            return None;
+        }
+
+        overrides.windows(2).find_map(|w| {
+            let (previous, current) = (w[0].1, w[1].1);
+            let lower = compare_versions(
+                version_bounds(&current.version).0,
+                version_bounds(&previous.version).0,
+            ) == Ok(std::cmp::Ordering::Less);
+
+            if lower {
+                Some(MetadataWarning::DecreasingOverrideVersion {
+                    key: dep.key.clone(),
+                    feature: current.key.clone(),
+                    version: current.version.clone(),
+                    previous_feature: previous.key.clone(),
+                    previous_version: previous.version.clone(),
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Sort the dependencies so each of them comes after the ones it `requires`,
+    /// keeping the original order otherwise.
+    ///
+    /// `table_key` is the path of the table defining the dependencies, used to report errors.
+    pub(crate) fn sort_by_requires(&mut self, table_key: &str) -> Result<(), MetadataError> {
+        for dep in self.deps.iter() {
+            if let Some(r) = dep
+                .requires
+                .iter()
+                .find(|r| !self.deps.iter().any(|d| &d.key == *r))
+            {
+                return Err(MetadataError::UnknownRequirement {
+                    key: format!("{}.{}", table_key, dep.key),
+                    required: r.clone(),
+                });
+            }
+        }
+
+        let mut remaining = std::mem::take(&mut self.deps);
+        while !remaining.is_empty() {
+            // A dependency is ready once all the entries defining the keys it requires are sorted
+            let ready = remaining.iter().position(|dep| {
+                dep.requires
+                    .iter()
+                    .all(|r| !remaining.iter().any(|d| &d.key == r))
+            });
+
+            match ready {
+                Some(i) => self.deps.push(remaining.remove(i)),
+                None => {
+                    return Err(MetadataError::RequirementCycle {
+                        keys: remaining.into_iter().map(|d| d.key).collect(),
+                    });
+                }
+            }
+        }
 
-        let deps = Self::parse_deps_table(meta, key, true)?;
+        Ok(())
+    }
+
+    // Replace the dependencies defined using `workspace = true` by the ones from `workspace`,
+    // with the settings defined by the crate taking precedence.
+    fn inherit(&mut self, workspace: &MetaData, table: &str) -> Result<(), MetadataError> {
+        for dep in self.deps.iter_mut().filter(|dep| dep.workspace) {
+            let mut inherited = workspace
+                .deps
+                .iter()
+                .find(|w| w.key == dep.key)
+                .cloned()
+                .ok_or_else(|| MetadataError::MissingKey {
+                    key: format!("workspace.metadata.{}.{}", table, dep.key),
+                })?;
+
+            inherited.cfg = match (inherited.cfg.take(), dep.cfg.take()) {
+                (Some(a), Some(b)) => Some(
+                    cfg_expr::Expression::parse(&format!(
+                        "all({}, {})",
+                        a.original(),
+                        b.original()
+                    ))
+                    .map_err(|error| {
+                        MetadataError::cfg_expr(
+                            &format!("workspace.metadata.{}.{}", table, dep.key),
+                            error,
+                        )
+                    })?,
+                ),
+                (a, b) => a.or(b),
+            };
+            if dep.version.is_some() {
+                inherited.version = dep.version.take();
+            }
+            if dep.name.is_some() {
+                inherited.name = dep.name.take();
+            }
+            if dep.fallback_names.is_some() {
+                inherited.fallback_names = dep.fallback_names.take();
+            }
+            if dep.feature.is_some() {
+                inherited.feature = dep.feature.take();
+            }
+            if !dep.alternatives.is_empty() {
+                inherited.alternatives = std::mem::take(&mut dep.alternatives);
+            }
+            inherited.optional |= dep.optional;
+            inherited
+                .version_overrides
+                .append(&mut dep.version_overrides);
+            inherited.cfg_overrides.append(&mut dep.cfg_overrides);
+            inherited.cfgs_if_found.append(&mut dep.cfgs_if_found);
+            inherited.requires.append(&mut dep.requires);
+
+            *dep = inherited;
+        }
 
-        Ok(MetaData { deps })
+        Ok(())
     }
 
     fn parse_deps_table(
         table: &Value,
         key: &str,
-        allow_cfg: bool,
+        cfg: Option<&cfg_expr::Expression>,
     ) -> Result<Vec<Dependency>, MetadataError> {
-        let table = table
-            .as_table()
-            .ok_or_else(|| MetadataError::NotATable(key.to_owned()))?;
+        let table = table.as_table().ok_or_else(|| MetadataError::NotATable {
+            key: key.to_owned(),
+        })?;
 
         let mut deps = Vec::new();
 
         for (name, value) in table {
             if name.starts_with("cfg(") {
-                if allow_cfg {
-                    let cfg_exp = cfg_expr::Expression::parse(name)?;
-
-                    for mut dep in
-                        Self::parse_deps_table(value, &format!("{}.{}", key, name), false)?
-                    {
-                        dep.cfg = Some(cfg_exp.clone());
-                        deps.push(dep);
-                    }
-                } else {
-                    return Err(MetadataError::NestedCfg(format!("{}.{}", key, name)));
-                }
+                let key = format!("{}.{}", key, name);
+                let cfg_exp = Self::parse_cfg(&key, name, cfg)?;
+
+                deps.extend(Self::parse_deps_table(value, &key, Some(&cfg_exp))?);
             } else {
-                let dep = Self::parse_dep(key, name, value)?;
+                let mut dep = Self::parse_dep(key, name, value)?;
+                dep.cfg = cfg.cloned();
                 deps.push(dep);
             }
         }
@@ -236,6 +1087,27 @@ impl MetaData {
         Ok(deps)
     }
 
+    // Parse the `cfg()` expression of the table `key`, combining it with the one
+    // of the parent table using `all()`
+    fn parse_cfg(
+        key: &str,
+        name: &str,
+        parent: Option<&cfg_expr::Expression>,
+    ) -> Result<cfg_expr::Expression, MetadataError> {
+        let invalid = |error| MetadataError::cfg_expr(key, error);
+        let cfg_exp = cfg_expr::Expression::parse(name).map_err(invalid)?;
+
+        match parent {
+            Some(parent) => cfg_expr::Expression::parse(&format!(
+                "all({}, {})",
+                parent.original(),
+                cfg_exp.original()
+            ))
+            .map_err(invalid),
+            None => Ok(cfg_exp),
+        }
+    }
+
     fn parse_dep(key: &str, name: &str, value: &Value) -> Result<Dependency, MetadataError> {
         let mut dep = Dependency::new(name);
 
@@ -243,11 +1115,11 @@ impl MetaData {
             // somelib = "1.0"
             toml::Value::String(ref s) => {
                 if !validate_version(s) {
-                    return Err(MetadataError::UnexpectedVersionSetting(
-                        key.into(),
-                        name.into(),
-                        value.type_str().to_owned(),
-                    ));
+                    return Err(MetadataError::UnexpectedVersionSetting {
+                        key: key.into(),
+                        setting: name.into(),
+                        value_type: value.type_str().to_owned(),
+                    });
                 }
 
                 dep.version = Some(s.clone());
@@ -256,7 +1128,9 @@ impl MetaData {
                 Self::parse_dep_table(key, name, &mut dep, t)?;
             }
             _ => {
-                return Err(MetadataError::NotStringOrTable(format!("{}.{}", key, name)));
+                return Err(MetadataError::NotStringOrTable {
+                    key: format!("{}.{}", key, name),
+                });
             }
         }
 
@@ -269,6 +1143,11 @@ impl MetaData {
         dep: &mut Dependency,
         t: &Map<String, Value>,
     ) -> Result<(), MetadataError> {
+        let dep_key = format!("{}.{}", p_key, name);
+        // Overrides from the `overrides` table come after the `v*` ones so they
+        // take precedence when requiring the same version.
+        let mut overrides = Vec::new();
+
         for (key, value) in t {
             match (key.as_str(), value) {
                 ("feature", toml::Value::String(s)) => {
@@ -276,11 +1155,11 @@ impl MetaData {
                 }
                 ("version", toml::Value::String(s)) => {
                     if !validate_version(s) {
-                        return Err(MetadataError::UnexpectedVersionSetting(
-                            format!("{}.{}", p_key, name),
-                            key.into(),
-                            value.type_str().to_owned(),
-                        ));
+                        return Err(MetadataError::UnexpectedVersionSetting {
+                            key: format!("{}.{}", p_key, name),
+                            setting: key.into(),
+                            value_type: value.type_str().to_owned(),
+                        });
                     }
 
                     dep.version = Some(s.clone());
@@ -289,62 +1168,229 @@ impl MetaData {
                     dep.name = Some(s.clone());
                 }
                 ("fallback-names", toml::Value::Array(values)) => {
-                    let key = format!("{}.{}", p_key, name);
+                    let key = format!("{}.{}.fallback-names", p_key, name);
                     dep.fallback_names = Some(Self::parse_name_list(&key, values)?);
                 }
                 ("optional", &toml::Value::Boolean(optional)) => {
                     dep.optional = optional;
                 }
+                ("workspace", &toml::Value::Boolean(workspace)) => {
+                    dep.workspace = workspace;
+                }
+                ("cfgs-if-found", toml::Value::Array(values)) => {
+                    let key = format!("{}.{}.cfgs-if-found", p_key, name);
+                    dep.cfgs_if_found = Self::parse_name_list(&key, values)?;
+                }
+                ("requires", toml::Value::Array(values)) => {
+                    let key = format!("{}.{}.requires", p_key, name);
+                    dep.requires = Self::parse_name_list(&key, values)?;
+                }
+                ("any-of", toml::Value::Array(values)) => {
+                    let key = format!("{}.{}.any-of", p_key, name);
+                    dep.alternatives = Self::parse_alternatives(&key, values)?;
+                }
+                (cfg, toml::Value::Table(cfg_settings)) if cfg.starts_with("cfg(") => {
+                    let key = format!("{}.{}.{}", p_key, name, cfg);
+                    let o = Self::parse_cfg_override(&key, cfg, cfg_settings)?;
+                    dep.cfg_overrides.push(o);
+                }
+                ("overrides", toml::Value::Table(features)) => {
+                    for (feature, settings) in features {
+                        let key = format!("{}.overrides.{}", dep_key, feature);
+                        let settings = settings
+                            .as_table()
+                            .ok_or_else(|| MetadataError::NotATable { key: key.clone() })?;
+                        overrides.push(Self::parse_version_override(&key, feature, settings)?);
+                    }
+                }
                 (version_feature, toml::Value::Table(version_settings))
-                    if version_feature.starts_with('v') =>
+                    if is_version_feature(version_feature) =>
                 {
-                    let mut builder = VersionOverrideBuilder::new(version_feature);
-
-                    for (k, v) in version_settings {
-                        match (k.as_str(), v) {
-                            ("version", toml::Value::String(feat_vers)) => {
-                                if !validate_version(feat_vers) {
-                                    return Err(MetadataError::UnexpectedVersionSetting(
-                                        format!("{}.{}", p_key, name),
-                                        k.into(),
-                                        v.type_str().to_owned(),
-                                    ));
-                                }
-
-                                builder.version = Some(feat_vers.into());
-                            }
-                            ("name", toml::Value::String(feat_name)) => {
-                                builder.full_name = Some(feat_name.into());
-                            }
-                            ("fallback-names", toml::Value::Array(values)) => {
-                                let key = format!("{}.{}.{}", p_key, name, version_feature);
-                                builder.fallback_names = Some(Self::parse_name_list(&key, values)?);
-                            }
-                            ("optional", &toml::Value::Boolean(optional)) => {
-                                builder.optional = Some(optional);
-                            }
-                            _ => {
-                                return Err(MetadataError::UnexpectedVersionSetting(
-                                    format!("{}.{}", p_key, name),
-                                    k.to_owned(),
-                                    v.type_str().to_owned(),
-                                ));
-                            }
-                        }
+                    let key = format!("{}.{}", dep_key, version_feature);
+                    dep.version_overrides.push(Self::parse_version_override(
+                        &key,
+                        version_feature,
+                        version_settings,
+                    )?);
+                }
+                _ => {
+                    return Err(MetadataError::UnexpectedKey {
+                        key: format!("{}.{}", p_key, name),
+                        setting: key.to_owned(),
+                        value_type: value.type_str().to_owned(),
+                    });
+                }
+            }
+        }
+
+        // The names of the libraries are defined by the alternatives
+        if !dep.alternatives.is_empty() {
+            for setting in ["name", "fallback-names"] {
+                if t.contains_key(setting) {
+                    return Err(MetadataError::ConflictingKeys {
+                        key: dep_key,
+                        setting: setting.to_string(),
+                        other: "any-of".to_string(),
+                    });
+                }
+            }
+        }
+
+        dep.version_overrides.append(&mut overrides);
+        Ok(())
+    }
+
+    // Parse the settings used when `feature` is enabled. `key` is the path of the override,
+    // used in errors.
+    fn parse_version_override(
+        key: &str,
+        feature: &str,
+        settings: &Map<String, Value>,
+    ) -> Result<VersionOverride, MetadataError> {
+        let mut builder = VersionOverrideBuilder::new(feature);
+
+        for (k, v) in settings {
+            match (k.as_str(), v) {
+                ("version", toml::Value::String(feat_vers)) => {
+                    if !validate_version(feat_vers) {
+                        return Err(MetadataError::UnexpectedVersionSetting {
+                            key: key.into(),
+                            setting: k.into(),
+                            value_type: v.type_str().to_owned(),
+                        });
                     }
 
-                    dep.version_overrides.push(builder.build()?);
+                    builder.version = Some(feat_vers.into());
+                }
+                ("name", toml::Value::String(feat_name)) => {
+                    builder.full_name = Some(feat_name.into());
+                }
+                ("fallback-names", toml::Value::Array(values)) => {
+                    let key = format!("{}.fallback-names", key);
+                    builder.fallback_names = Some(Self::parse_name_list(&key, values)?);
+                }
+                ("optional", &toml::Value::Boolean(optional)) => {
+                    builder.optional = Some(optional);
                 }
                 _ => {
-                    return Err(MetadataError::UnexpectedKey(
-                        format!("{}.{}", p_key, name),
-                        key.to_owned(),
-                        value.type_str().to_owned(),
-                    ));
+                    return Err(MetadataError::UnexpectedVersionSetting {
+                        key: key.into(),
+                        setting: k.to_owned(),
+                        value_type: v.type_str().to_owned(),
+                    });
                 }
             }
         }
-        Ok(())
+
+        builder.build(key)
+    }
+
+    fn parse_cfg_override(
+        key: &str,
+        cfg: &str,
+        t: &Map<String, Value>,
+    ) -> Result<CfgOverride, MetadataError> {
+        let mut o = CfgOverride {
+            cfg: Self::parse_cfg(key, cfg, None)?,
+            version: None,
+            name: None,
+            fallback_names: None,
+            optional: None,
+        };
+
+        for (k, v) in t {
+            match (k.as_str(), v) {
+                ("version", toml::Value::String(s)) => {
+                    if !validate_version(s) {
+                        return Err(MetadataError::UnexpectedVersionSetting {
+                            key: key.into(),
+                            setting: k.into(),
+                            value_type: v.type_str().to_owned(),
+                        });
+                    }
+
+                    o.version = Some(s.clone());
+                }
+                ("name", toml::Value::String(s)) => {
+                    o.name = Some(s.clone());
+                }
+                ("fallback-names", toml::Value::Array(values)) => {
+                    let key = format!("{}.fallback-names", key);
+                    o.fallback_names = Some(Self::parse_name_list(&key, values)?);
+                }
+                ("optional", &toml::Value::Boolean(optional)) => {
+                    o.optional = Some(optional);
+                }
+                _ => {
+                    return Err(MetadataError::UnexpectedKey {
+                        key: key.into(),
+                        setting: k.to_owned(),
+                        value_type: v.type_str().to_owned(),
+                    });
+                }
+            }
+        }
+
+        Ok(o)
+    }
+
+    fn parse_alternatives(key: &str, values: &[Value]) -> Result<Vec<Alternative>, MetadataError> {
+        let mut alternatives = Vec::new();
+
+        for (i, value) in values.iter().enumerate() {
+            let key = format!("{}[{}]", key, i);
+            let t = value
+                .as_table()
+                .ok_or_else(|| MetadataError::NotATable { key: key.clone() })?;
+
+            let mut name = None;
+            let mut alternative = Alternative {
+                name: String::new(),
+                version: None,
+                feature: None,
+                fallback_names: None,
+            };
+
+            for (k, v) in t {
+                match (k.as_str(), v) {
+                    ("name", toml::Value::String(s)) => {
+                        name = Some(s.clone());
+                    }
+                    ("version", toml::Value::String(s)) => {
+                        if !validate_version(s) {
+                            return Err(MetadataError::UnexpectedVersionSetting {
+                                key,
+                                setting: k.into(),
+                                value_type: v.type_str().to_owned(),
+                            });
+                        }
+
+                        alternative.version = Some(s.clone());
+                    }
+                    ("feature", toml::Value::String(s)) => {
+                        alternative.feature = Some(s.clone());
+                    }
+                    ("fallback-names", toml::Value::Array(values)) => {
+                        let key = format!("{}.fallback-names", key);
+                        alternative.fallback_names = Some(Self::parse_name_list(&key, values)?);
+                    }
+                    _ => {
+                        return Err(MetadataError::UnexpectedKey {
+                            key,
+                            setting: k.to_owned(),
+                            value_type: v.type_str().to_owned(),
+                        });
+                    }
+                }
+            }
+
+            alternative.name = name.ok_or_else(|| MetadataError::MissingKey {
+                key: format!("{}.name", key),
+            })?;
+            alternatives.push(alternative);
+        }
+
+        Ok(alternatives)
     }
 
     fn parse_name_list(key: &str, values: &[Value]) -> Result<Vec<String>, MetadataError> {
@@ -355,7 +1401,9 @@ impl MetaData {
                 value
                     .as_str()
                     .map(|x| x.to_owned())
-                    .ok_or_else(|| MetadataError::NotString(format!("{}[{}]", key, i)))
+                    .ok_or_else(|| MetadataError::NotString {
+                        key: format!("{}[{}]", key, i),
+                    })
             })
             .collect()
     }
@@ -395,7 +1443,13 @@ impl<'a> std::ops::RangeBounds<&'a str> for VersionRange<'a> {
     }
 }
 
-pub(crate) fn parse_version(version: &str) -> VersionRange {
+// Keys in the form of `v1_2` define the settings used when the feature with the same name is enabled
+fn is_version_feature(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars.next() == Some('v') && matches!(chars.next(), Some(c) if c.is_ascii_digit())
+}
+
+pub(crate) fn parse_version(version: &str) -> VersionRange<'_> {
     if let Some((min, max)) = version.split_once(',') {
         // Format checked when parsing
         let min = min.trim_start().strip_prefix(">=").unwrap().trim();
@@ -408,6 +1462,59 @@ pub(crate) fn parse_version(version: &str) -> VersionRange {
     }
 }
 
+// Return the lower and, if any, the upper bound of `version`
+fn version_bounds(version: &str) -> (&str, Option<&str>) {
+    match parse_version(version) {
+        VersionRange::Range(r) => (r.start.trim(), Some(r.end.trim())),
+        VersionRange::RangeFrom(r) => (r.start.trim(), None),
+    }
+}
+
+pub(crate) fn compare_versions(a: &str, b: &str) -> Result<std::cmp::Ordering, String> {
+    version_compare::compare(a, b)
+        .ok()
+        .and_then(|cmp| cmp.ord())
+        .ok_or_else(|| format!("cannot compare versions `{}` and `{}`", a, b))
+}
+
+// Intersect the version ranges in `versions`, returning the resulting range and the index of
+// the strictest one: requiring the highest minimum version, the last one if several are equal.
+pub(crate) fn intersect_versions(versions: &[&str]) -> Result<(String, usize), String> {
+    let mut strictest = 0;
+    let (mut min, mut max) = version_bounds(versions[0]);
+
+    for (i, version) in versions.iter().enumerate().skip(1) {
+        let (v_min, v_max) = version_bounds(version);
+        if compare_versions(v_min, min)? != std::cmp::Ordering::Less {
+            strictest = i;
+            min = v_min;
+        }
+        if let Some(v_max) = v_max {
+            max = match max {
+                Some(max) if compare_versions(max, v_max)? == std::cmp::Ordering::Less => Some(max),
+                _ => Some(v_max),
+            };
+        }
+    }
+
+    match max {
+        Some(max) if compare_versions(min, max)? != std::cmp::Ordering::Less => Err(format!(
+            "no version matches all of {}",
+            versions
+                .iter()
+                .map(|v| format!("`{}`", v))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        // Keep the version as written when it is already the resulting range
+        _ if version_bounds(versions[strictest]) == (min, max) => {
+            Ok((versions[strictest].to_string(), strictest))
+        }
+        Some(max) => Ok((format!(">= {}, < {}", min, max), strictest)),
+        None => Ok((min.to_string(), strictest)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -424,7 +1531,7 @@ mod tests {
         p.push("Cargo.toml");
         assert!(p.exists());
 
-        MetaData::from_file(&p)
+        MetaData::from_manifest(&Manifest::read(&p)?, "system-deps", true)
     }
 
     #[test]
@@ -452,7 +1559,8 @@ mod tests {
                         feature: Some("another-test-feature".into()),
                         ..Default::default()
                     }
-                ]
+                ],
+                ..Default::default()
             }
         )
     }
@@ -461,7 +1569,11 @@ mod tests {
     fn parse_feature_not_string() {
         assert_matches!(
             parse_file("toml-feature-not-string"),
-            Err(crate::Error::InvalidMetadata(_))
+            Err(crate::Error::InvalidManifest {
+                error: MetadataError::UnexpectedKey { .. },
+                location: Some(_),
+                ..
+            })
         );
     }
 
@@ -484,7 +1596,8 @@ mod tests {
                         optional: None,
                     }],
                     ..Default::default()
-                },]
+                },],
+                ..Default::default()
             }
         )
     }
@@ -516,7 +1629,8 @@ mod tests {
                         },
                     ],
                     ..Default::default()
-                },]
+                },],
+                ..Default::default()
             }
         )
     }
@@ -539,7 +1653,8 @@ mod tests {
                     ]),
                     version_overrides: vec![],
                     ..Default::default()
-                }]
+                }],
+                ..Default::default()
             }
         )
     }
@@ -584,7 +1699,8 @@ mod tests {
                         },
                     ],
                     ..Default::default()
-                }]
+                }],
+                ..Default::default()
             }
         )
     }
@@ -628,7 +1744,8 @@ mod tests {
                         },],
                         ..Default::default()
                     },
-                ]
+                ],
+                ..Default::default()
             }
         )
     }
@@ -660,8 +1777,138 @@ mod tests {
                         optional: true,
                         ..Default::default()
                     },
-                ]
+                ],
+                ..Default::default()
             }
         )
     }
+
+    #[test]
+    fn parse_cfg_nested() {
+        let m = parse_file("toml-cfg-nested").unwrap();
+
+        assert_eq!(
+            m.deps[0],
+            Dependency {
+                key: "testlib".into(),
+                version: Some("1".into()),
+                cfg: Some(Expression::parse("all(unix, target_os = \"linux\")").unwrap()),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_cfg_override() {
+        let m = parse_file("toml-cfg-override").unwrap();
+
+        assert_eq!(
+            m.deps[0].cfg_overrides,
+            vec![
+                CfgOverride {
+                    cfg: Expression::parse("target_os = \"macos\"").unwrap(),
+                    version: Some("2.0".into()),
+                    name: Some("testlib-2.0".into()),
+                    fallback_names: None,
+                    optional: None,
+                },
+                CfgOverride {
+                    cfg: Expression::parse("windows").unwrap(),
+                    version: Some("3.0".into()),
+                    name: None,
+                    fallback_names: Some(vec!["testlib-3.0".into()]),
+                    optional: Some(true),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_any_of() {
+        let m = parse_file("toml-any-of").unwrap();
+
+        assert_eq!(
+            m.deps[0].alternatives,
+            vec![
+                Alternative {
+                    name: "nosuchlib".into(),
+                    version: Some("1".into()),
+                    feature: None,
+                    fallback_names: None,
+                },
+                Alternative {
+                    name: "testlib".into(),
+                    version: Some("1.2".into()),
+                    feature: Some("another-test-feature".into()),
+                    fallback_names: None,
+                },
+                Alternative {
+                    name: "testdata".into(),
+                    version: Some("4".into()),
+                    feature: None,
+                    fallback_names: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_cfgs_if_found() {
+        let m = parse_file("toml-cfgs-if-found").unwrap();
+
+        assert_eq!(
+            m.deps[1],
+            Dependency {
+                key: "testlib".into(),
+                version: Some("1".into()),
+                cfgs_if_found: vec!["have_testlib".into(), "testlib_is_cool".into()],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_workspace() {
+        let m = parse_file("toml-workspace/member").unwrap();
+
+        assert_eq!(
+            m.deps[0],
+            Dependency {
+                key: "test_data".into(),
+                version: Some("4".into()),
+                name: Some("testdata".into()),
+                feature: Some("test-feature".into()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            m.workspace_manifest,
+            Some(
+                Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap())
+                    .join("src/tests/toml-workspace/Cargo.toml")
+            )
+        );
+    }
+
+    #[test]
+    fn intersect_override_versions() {
+        assert_eq!(intersect_versions(&["1.2"]).unwrap(), ("1.2".into(), 0));
+        assert_eq!(
+            intersect_versions(&["1.4", ">= 1.2, < 2", "1.0"]).unwrap(),
+            (">= 1.4, < 2".into(), 0)
+        );
+        assert_eq!(
+            intersect_versions(&[">= 1.2, < 3", ">= 1.0, < 2"]).unwrap(),
+            (">= 1.2, < 2".into(), 0)
+        );
+        // The last of the strictest ones is picked
+        assert_eq!(
+            intersect_versions(&["1.2", "1.2"]).unwrap(),
+            ("1.2".into(), 1)
+        );
+        assert_eq!(
+            intersect_versions(&[">= 1.0, < 2", "3"]).unwrap_err(),
+            "no version matches all of `>= 1.0, < 2`, `3`"
+        );
+    }
 }
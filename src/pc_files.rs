@@ -0,0 +1,380 @@
+// Locate the `.pc` files `pkg-config` reads when resolving a library, using the same
+// environment variables as the `pkg-config` crate.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Mutex, RwLock},
+};
+
+use crate::EnvSource;
+
+// Environment variables read by pkg-config which may change the resolved libraries.
+const PKG_CONFIG_ENV_VARIABLES: [&str; 4] = [
+    "PKG_CONFIG",
+    "PKG_CONFIG_PATH",
+    "PKG_CONFIG_LIBDIR",
+    "PKG_CONFIG_SYSROOT_DIR",
+];
+
+// Environment variables only read by the `pkg-config` crate
+const PKG_CONFIG_CRATE_VARIABLES: [&str; 1] = ["PKG_CONFIG_ALLOW_CROSS"];
+
+// Held for writing while the process environment is modified by `with_process_env`
+static PROCESS_ENV: RwLock<()> = RwLock::new(());
+
+// Built-in search path of each pkg-config executable, which does not depend on the environment
+static DEFAULT_SEARCH_PATHS: Mutex<Vec<(String, Vec<PathBuf>)>> = Mutex::new(Vec::new());
+
+// The names under which the variable `base` is looked for, by order of precedence.
+// Like the `pkg-config` crate, the target-specific variants are only used if both
+// `TARGET` and `HOST` are defined.
+pub(crate) fn variable_names(env: &dyn EnvSource, base: &str) -> Vec<String> {
+    match (env.var("TARGET"), env.var("HOST")) {
+        (Some(target), Some(host)) => {
+            let kind = if host == target { "HOST" } else { "TARGET" };
+            vec![
+                format!("{}_{}", base, target),
+                format!("{}_{}", base, target.replace('-', "_")),
+                format!("{}_{}", kind, base),
+                base.to_string(),
+            ]
+        }
+        _ => vec![base.to_string()],
+    }
+}
+
+/// All the environment variables which may change the libraries found by pkg-config
+pub(crate) fn env_variables(env: &dyn EnvSource) -> Vec<String> {
+    PKG_CONFIG_ENV_VARIABLES
+        .iter()
+        .flat_map(|base| variable_names(env, base))
+        .collect()
+}
+
+/// The value of the pkg-config variable `base`, using its target-specific variants if defined
+pub(crate) fn var(env: &dyn EnvSource, base: &str) -> Option<String> {
+    variable_names(env, base)
+        .iter()
+        .find_map(|name| env.var(name))
+}
+
+/// The `pkg-config` executable to run
+pub(crate) fn executable(env: &dyn EnvSource) -> String {
+    var(env, "PKG_CONFIG").unwrap_or_else(|| "pkg-config".to_string())
+}
+
+// The variables read by the `pkg-config` crate when probing `name`, with their value in `env`.
+// `extra_dirs` are appended to `PKG_CONFIG_PATH`.
+fn probe_variables(
+    env: &dyn EnvSource,
+    name: &str,
+    extra_dirs: &[PathBuf],
+) -> Result<Vec<(String, Option<OsString>)>, env::JoinPathsError> {
+    let mut names = vec!["TARGET".to_string(), "HOST".to_string()];
+    names.extend(env_variables(env));
+    names.extend(
+        PKG_CONFIG_CRATE_VARIABLES
+            .iter()
+            .flat_map(|base| variable_names(env, base)),
+    );
+    names.push(format!(
+        "{}_NO_PKG_CONFIG",
+        name.to_ascii_uppercase().replace('-', "_")
+    ));
+
+    let mut variables: Vec<_> = names
+        .into_iter()
+        .map(|name| {
+            let value = env.var(&name).map(OsString::from);
+            (name, value)
+        })
+        .collect();
+
+    if !extra_dirs.is_empty() {
+        // Extend the variable the `pkg-config` crate is going to use
+        let path_names = variable_names(env, "PKG_CONFIG_PATH");
+        let name = path_names
+            .iter()
+            .find(|name| env.var(name).is_some())
+            .unwrap_or_else(|| path_names.last().unwrap());
+        let (_, value) = variables.iter_mut().find(|(n, _)| n == name).unwrap();
+        let mut dirs: Vec<_> = value
+            .as_ref()
+            .map(|path| env::split_paths(path).collect())
+            .unwrap_or_default();
+        dirs.extend(extra_dirs.iter().cloned());
+        *value = Some(env::join_paths(dirs)?);
+    }
+
+    Ok(variables)
+}
+
+/// Run `f`, which probes `name` using the `pkg-config` crate, with the variables it reads set in
+/// the process environment to their value in `env`, as it does not read any other environment.
+/// `extra_dirs` are appended to `PKG_CONFIG_PATH`. The variables are restored afterwards.
+///
+/// The process environment is not modified if it already matches, which is always the case
+/// when probing with [crate::ProcessEnv] without extra directories.
+pub(crate) fn with_process_env<T>(
+    env: &dyn EnvSource,
+    name: &str,
+    extra_dirs: &[PathBuf],
+    f: impl FnOnce() -> T,
+) -> Result<T, env::JoinPathsError> {
+    let changed = |variables: &[(String, Option<OsString>)]| {
+        variables
+            .iter()
+            .any(|(name, value)| env::var_os(name) != *value)
+    };
+
+    {
+        // `env` may be reading the process environment, so it cannot be modified meanwhile
+        let _lock = PROCESS_ENV.read().unwrap_or_else(|e| e.into_inner());
+        if !changed(&probe_variables(env, name, extra_dirs)?) {
+            return Ok(f());
+        }
+    }
+
+    let _lock = PROCESS_ENV.write().unwrap_or_else(|e| e.into_inner());
+    let _restore = RestoreEnv(
+        probe_variables(env, name, extra_dirs)?
+            .into_iter()
+            .filter(|(name, value)| env::var_os(name) != *value)
+            .map(|(name, value)| {
+                let previous = env::var_os(&name);
+                set_var(&name, value.as_ref());
+                (name, previous)
+            })
+            .collect(),
+    );
+    Ok(f())
+}
+
+// Restore the variables of the process environment when dropped, even if the probe panicked
+struct RestoreEnv(Vec<(String, Option<OsString>)>);
+
+impl Drop for RestoreEnv {
+    fn drop(&mut self) {
+        for (name, value) in &self.0 {
+            set_var(name, value.as_ref());
+        }
+    }
+}
+
+fn set_var(name: &str, value: Option<&OsString>) {
+    match value {
+        Some(value) => env::set_var(name, value),
+        None => env::remove_var(name),
+    }
+}
+
+/// Find the `.pc` file defining `name` and the ones of all the modules it requires,
+/// looking into `extra_dirs` after the directories listed in `PKG_CONFIG_PATH`.
+///
+/// This is best effort: modules which cannot be located are silently skipped.
+pub(crate) fn resolve(
+    env: &dyn EnvSource,
+    name: &str,
+    statik: bool,
+    extra_dirs: &[PathBuf],
+) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(path) = var(env, "PKG_CONFIG_PATH") {
+        dirs.extend(env::split_paths(&path));
+    }
+    dirs.extend_from_slice(extra_dirs);
+    match var(env, "PKG_CONFIG_LIBDIR") {
+        Some(libdir) => dirs.extend(env::split_paths(&libdir)),
+        None => dirs.extend(default_search_path(&executable(env))),
+    }
+    dirs.retain(|dir| !dir.as_os_str().is_empty());
+
+    let mut pc_files = Vec::new();
+    let mut visited = vec![name.to_string()];
+    let mut queue = VecDeque::from(vec![name.to_string()]);
+
+    while let Some(module) = queue.pop_front() {
+        let pc_file = match dirs
+            .iter()
+            .map(|dir| dir.join(format!("{}.pc", module)))
+            .find(|path| path.is_file())
+        {
+            Some(pc_file) => pc_file,
+            None => continue,
+        };
+
+        if let Ok(content) = fs::read_to_string(&pc_file) {
+            let dir = pc_file.parent().unwrap_or_else(|| Path::new(""));
+            for required in requires(&content, dir, statik) {
+                if !visited.contains(&required) {
+                    visited.push(required.clone());
+                    queue.push_back(required);
+                }
+            }
+        }
+        pc_files.push(pc_file);
+    }
+
+    pc_files
+}
+
+/// The directories `pkg_config` looks into if `PKG_CONFIG_LIBDIR` is not defined
+pub(crate) fn default_search_path(pkg_config: &str) -> Vec<PathBuf> {
+    let mut cache = DEFAULT_SEARCH_PATHS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some((_, dirs)) = cache.iter().find(|(exe, _)| exe == pkg_config) {
+        return dirs.clone();
+    }
+
+    let run = |exe: &str| {
+        Command::new(exe)
+            .args(["--variable=pc_path", "pkg-config"])
+            .output()
+    };
+    // The `pkg-config` crate falls back to `pkgconf` if `pkg-config` is not installed
+    let output = match run(pkg_config) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && pkg_config == "pkg-config" => {
+            run("pkgconf")
+        }
+        output => output,
+    };
+    let dirs = match output {
+        Ok(output) if output.status.success() => {
+            let path = String::from_utf8_lossy(&output.stdout);
+            env::split_paths(path.trim()).collect()
+        }
+        _ => Vec::new(),
+    };
+
+    cache.push((pkg_config.to_string(), dirs.clone()));
+    dirs
+}
+
+// The modules listed in the `Requires` field of a `.pc` file, and in `Requires.private`
+// if `statik` is set. `dir` is the directory containing the file.
+fn requires(content: &str, dir: &Path, statik: bool) -> Vec<String> {
+    let mut variables = HashMap::new();
+    variables.insert("pcfiledir".to_string(), dir.to_string_lossy().to_string());
+    let mut modules = Vec::new();
+
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let (name, separator, value) = match line.find([':', '=']) {
+            Some(i) => (line[..i].trim(), &line[i..i + 1], line[i + 1..].trim()),
+            None => continue,
+        };
+        let value = expand(value, &variables);
+
+        match (separator, name) {
+            ("=", _) => {
+                variables.insert(name.to_string(), value);
+            }
+            (_, "Requires") => modules.extend(parse_requires(&value)),
+            (_, "Requires.private") if statik => modules.extend(parse_requires(&value)),
+            _ => {}
+        }
+    }
+
+    modules
+}
+
+// Parse a list of modules in the form `module [op version]`, separated by commas or spaces
+fn parse_requires(value: &str) -> Vec<String> {
+    const OPERATORS: [char; 4] = ['<', '>', '=', '!'];
+
+    let mut modules = Vec::new();
+    let mut tokens = value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|t| !t.is_empty());
+
+    while let Some(token) = tokens.next() {
+        let (module, op) = token.split_at(token.find(OPERATORS).unwrap_or(token.len()));
+        if !module.is_empty() {
+            modules.push(module.to_string());
+        }
+        // the version follows the operator, unless they are not separated
+        if !op.is_empty() && op.trim_start_matches(OPERATORS).is_empty() {
+            tokens.next();
+        }
+    }
+
+    modules
+}
+
+// Replace the `${name}` references to the variables defined earlier in the file
+fn expand(value: &str, variables: &HashMap<String, String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        match rest[start + 2..].find('}') {
+            Some(len) => {
+                let name = &rest[start + 2..start + 2 + len];
+                if let Some(value) = variables.get(name) {
+                    expanded.push_str(value);
+                }
+                rest = &rest[start + 3 + len..];
+            }
+            None => {
+                expanded.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    expanded.push_str(rest);
+
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_requires_field() {
+        let content = "\
+prefix=/usr
+gtk=gtk4
+# Requires: commented
+Requires: glib-2.0 >= 2.50, ${gtk}, cairo>=1.0 pango= 1.2 gio-2.0
+Requires.private: libffi
+";
+        assert_eq!(
+            requires(content, Path::new("/usr/lib/pkgconfig"), false),
+            vec!["glib-2.0", "gtk4", "cairo", "pango", "gio-2.0"]
+        );
+        assert_eq!(
+            requires(content, Path::new("/usr/lib/pkgconfig"), true),
+            vec!["glib-2.0", "gtk4", "cairo", "pango", "gio-2.0", "libffi"]
+        );
+    }
+
+    #[test]
+    fn target_specific_variables() {
+        let mut env = HashMap::new();
+        env.insert("PKG_CONFIG_PATH", "/host".to_string());
+        assert_eq!(var(&env, "PKG_CONFIG_PATH").as_deref(), Some("/host"));
+
+        env.insert("TARGET", "aarch64-unknown-linux-gnu".to_string());
+        env.insert("HOST", "x86_64-unknown-linux-gnu".to_string());
+        env.insert("TARGET_PKG_CONFIG_PATH", "/target".to_string());
+        assert_eq!(var(&env, "PKG_CONFIG_PATH").as_deref(), Some("/target"));
+
+        env.insert(
+            "PKG_CONFIG_PATH_aarch64_unknown_linux_gnu",
+            "/aarch64".to_string(),
+        );
+        assert_eq!(var(&env, "PKG_CONFIG_PATH").as_deref(), Some("/aarch64"));
+
+        assert!(env_variables(&env)
+            .contains(&"PKG_CONFIG_LIBDIR_aarch64-unknown-linux-gnu".to_string()));
+        assert!(env_variables(&env).contains(&"TARGET_PKG_CONFIG_SYSROOT_DIR".to_string()));
+    }
+}
@@ -0,0 +1,473 @@
+// Generate a Software Bill of Materials listing the system libraries found
+
+use std::{
+    fmt::{self, Write},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{Dependencies, Error, InternalLib, Library};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Format of the Software Bill of Materials generated by [Dependencies::sbom]
+pub enum SbomFormat {
+    /// [SPDX 2.3](https://spdx.github.io/spdx-spec/v2.3/) JSON document
+    Spdx,
+    /// [CycloneDX 1.5](https://cyclonedx.org/docs/1.5/json/) JSON document
+    CycloneDx,
+}
+
+impl SbomFormat {
+    /// Name of the file the SBOM is written to in `OUT_DIR` by [crate::Config::probe]
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::Spdx => "system-deps.spdx.json",
+            Self::CycloneDx => "system-deps.cdx.json",
+        }
+    }
+}
+
+// SBOMs are meant to be reproducible so never include the actual creation time
+const CREATED: &str = "1970-01-01T00:00:00Z";
+
+const TOOL: &str = concat!("system-deps-", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Linkage {
+    Static,
+    Dynamic,
+    // Only some of the libraries are available as static archives
+    Mixed,
+}
+
+// How the libraries of `lib` are linked, matching the flags emitted by `gen_flags`: they are
+// only linked statically if a static archive is available.
+fn linkage(lib: &Library) -> Linkage {
+    let statik = lib.libs.iter().filter(|l| is_static(lib, l)).count();
+    if statik == 0 {
+        Linkage::Dynamic
+    } else if statik == lib.libs.len() {
+        Linkage::Static
+    } else {
+        Linkage::Mixed
+    }
+}
+
+fn is_static(lib: &Library, l: &InternalLib) -> bool {
+    lib.statik && l.is_static_available
+}
+
+impl Linkage {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Static => "static",
+            Self::Dynamic => "dynamic",
+            Self::Mixed => "mixed",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Self::Static => "Statically linked system library",
+            Self::Dynamic => "Dynamically linked system library",
+            Self::Mixed => "Partly statically linked system library",
+        }
+    }
+
+    // The SPDX relationships from the crate to the library, both if it is partly static
+    fn spdx_relationships(&self) -> &'static [&'static str] {
+        match self {
+            Self::Static => &["STATIC_LINK"],
+            Self::Dynamic => &["DYNAMIC_LINK"],
+            Self::Mixed => &["STATIC_LINK", "DYNAMIC_LINK"],
+        }
+    }
+}
+
+// The .pc file the library has been resolved from, or the directory containing it
+// if it has been defined using environment variables.
+fn source_path(lib: &Library) -> Option<&PathBuf> {
+    lib.pc_files.first().or_else(|| lib.link_paths.first())
+}
+
+// SPDX identifiers may only contain letters, numbers, '.' and '-'.
+// A numeric suffix is added if the id is already in `used`, as different keys such as
+// `foo_bar` and `foo-bar` may map to the same id.
+fn spdx_id(key: &str, used: &mut Vec<String>) -> String {
+    let id: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let base = format!("SPDXRef-Package-{}", id);
+
+    let mut id = base.clone();
+    let mut suffix = 1;
+    while used.contains(&id) {
+        suffix += 1;
+        id = format!("{}-{}", base, suffix);
+    }
+    used.push(id.clone());
+    id
+}
+
+#[derive(Debug)]
+enum Json {
+    Str(String),
+    Bool(bool),
+    Int(u32),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    fn str(s: impl Into<String>) -> Self {
+        Self::Str(s.into())
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent + 1);
+        let end = "  ".repeat(indent);
+
+        match self {
+            Self::Str(s) => write_str(f, s),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Array(v) if v.is_empty() => write!(f, "[]"),
+            Self::Array(v) => {
+                writeln!(f, "[")?;
+                for (i, value) in v.iter().enumerate() {
+                    write!(f, "{}", pad)?;
+                    value.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < v.len() { "," } else { "" })?;
+                }
+                write!(f, "{}]", end)
+            }
+            Self::Object(v) if v.is_empty() => write!(f, "{{}}"),
+            Self::Object(v) => {
+                writeln!(f, "{{")?;
+                for (i, (key, value)) in v.iter().enumerate() {
+                    write!(f, "{}", pad)?;
+                    write_str(f, key)?;
+                    write!(f, ": ")?;
+                    value.write(f, indent + 1)?;
+                    writeln!(f, "{}", if i + 1 < v.len() { "," } else { "" })?;
+                }
+                write!(f, "{}}}", end)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+fn spdx(libraries: &Dependencies, package: &str, version: &str) -> Json {
+    let mut ids = Vec::new();
+    let root = spdx_id(package, &mut ids);
+
+    let mut packages = vec![Json::Object(vec![
+        ("SPDXID", Json::str(&root)),
+        ("name", Json::str(package)),
+        ("versionInfo", Json::str(version)),
+        ("downloadLocation", Json::str("NOASSERTION")),
+        ("filesAnalyzed", Json::Bool(false)),
+    ])];
+    let mut relationships = vec![Json::Object(vec![
+        ("spdxElementId", Json::str("SPDXRef-DOCUMENT")),
+        ("relationshipType", Json::str("DESCRIBES")),
+        ("relatedSpdxElement", Json::str(&root)),
+    ])];
+
+    for (key, lib) in libraries.iter() {
+        let id = spdx_id(&format!("system-deps-{}", key), &mut ids);
+
+        let mut fields = vec![
+            ("SPDXID", Json::str(&id)),
+            ("name", Json::str(&lib.name)),
+            ("versionInfo", Json::str(&lib.version)),
+            ("downloadLocation", Json::str("NOASSERTION")),
+            ("filesAnalyzed", Json::Bool(false)),
+            ("primaryPackagePurpose", Json::str("LIBRARY")),
+        ];
+        if let Some(path) = source_path(lib) {
+            fields.push((
+                "sourceInfo",
+                Json::str(format!("resolved from {}", path.display())),
+            ));
+        }
+        let linkage = linkage(lib);
+        fields.push(("comment", Json::str(linkage.description())));
+        packages.push(Json::Object(fields));
+
+        // `A STATIC_LINK B` means that A statically links B
+        for relationship in linkage.spdx_relationships() {
+            relationships.push(Json::Object(vec![
+                ("spdxElementId", Json::str(&root)),
+                ("relationshipType", Json::str(*relationship)),
+                ("relatedSpdxElement", Json::str(&id)),
+            ]));
+        }
+    }
+
+    Json::Object(vec![
+        ("spdxVersion", Json::str("SPDX-2.3")),
+        ("dataLicense", Json::str("CC0-1.0")),
+        ("SPDXID", Json::str("SPDXRef-DOCUMENT")),
+        (
+            "name",
+            Json::str(format!("{}-{}-system-deps", package, version)),
+        ),
+        (
+            "documentNamespace",
+            Json::str(format!(
+                "https://spdx.org/spdxdocs/{}-{}-system-deps",
+                package, version
+            )),
+        ),
+        (
+            "creationInfo",
+            Json::Object(vec![
+                ("created", Json::str(CREATED)),
+                (
+                    "creators",
+                    Json::Array(vec![Json::str(format!("Tool: {}", TOOL))]),
+                ),
+            ]),
+        ),
+        ("packages", Json::Array(packages)),
+        ("relationships", Json::Array(relationships)),
+    ])
+}
+
+fn cyclonedx(libraries: &Dependencies, package: &str, version: &str) -> Json {
+    let property = |name: &str, value: String| {
+        Json::Object(vec![
+            ("name", Json::str(format!("system-deps:{}", name))),
+            ("value", Json::Str(value)),
+        ])
+    };
+
+    let mut components = Vec::new();
+    let mut refs = Vec::new();
+
+    for (key, lib) in libraries.iter() {
+        let bom_ref = format!("system-deps:{}", key);
+
+        let linkage = linkage(lib);
+        let mut properties = vec![
+            property("key", key.to_string()),
+            property("linkage", linkage.name().to_string()),
+        ];
+        if linkage == Linkage::Mixed {
+            let static_libs: Vec<_> = lib
+                .libs
+                .iter()
+                .filter(|l| is_static(lib, l))
+                .map(|l| l.name.as_str())
+                .collect();
+            properties.push(property("static-libs", static_libs.join(" ")));
+        }
+        if let Some(path) = source_path(lib) {
+            properties.push(property("source", path.display().to_string()));
+        }
+
+        components.push(Json::Object(vec![
+            ("type", Json::str("library")),
+            ("bom-ref", Json::str(&bom_ref)),
+            ("name", Json::str(&lib.name)),
+            ("version", Json::str(&lib.version)),
+            ("scope", Json::str("required")),
+            ("properties", Json::Array(properties)),
+        ]));
+        refs.push(Json::Str(bom_ref));
+    }
+
+    Json::Object(vec![
+        ("bomFormat", Json::str("CycloneDX")),
+        ("specVersion", Json::str("1.5")),
+        ("version", Json::Int(1)),
+        (
+            "metadata",
+            Json::Object(vec![
+                ("timestamp", Json::str(CREATED)),
+                (
+                    "tools",
+                    Json::Object(vec![(
+                        "components",
+                        Json::Array(vec![Json::Object(vec![
+                            ("type", Json::str("application")),
+                            ("name", Json::str("system-deps")),
+                            ("version", Json::str(env!("CARGO_PKG_VERSION"))),
+                        ])]),
+                    )]),
+                ),
+                (
+                    "component",
+                    Json::Object(vec![
+                        ("type", Json::str("library")),
+                        ("bom-ref", Json::str(package)),
+                        ("name", Json::str(package)),
+                        ("version", Json::str(version)),
+                    ]),
+                ),
+            ]),
+        ),
+        ("components", Json::Array(components)),
+        (
+            "dependencies",
+            Json::Array(vec![Json::Object(vec![
+                ("ref", Json::str(package)),
+                ("dependsOn", Json::Array(refs)),
+            ])]),
+        ),
+    ])
+}
+
+pub(crate) fn generate(
+    libraries: &Dependencies,
+    format: SbomFormat,
+    package: &str,
+    version: &str,
+) -> String {
+    let doc = match format {
+        SbomFormat::Spdx => spdx(libraries, package, version),
+        SbomFormat::CycloneDx => cyclonedx(libraries, package, version),
+    };
+    format!("{}\n", doc)
+}
+
+// Write the SBOM in all the supported formats to `out_dir`
+pub(crate) fn write(
+    libraries: &Dependencies,
+    out_dir: &Path,
+    package: &str,
+    version: &str,
+) -> Result<(), Error> {
+    for format in [SbomFormat::Spdx, SbomFormat::CycloneDx] {
+        let path = out_dir.join(format.file_name());
+        fs::write(&path, generate(libraries, format, package, version))
+            .map_err(|e| Error::FailToWrite(format!("error writing {}", path.display()), e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(name: &str, statik: bool) -> Library {
+        let mut lib = Library::from_env_variables(name);
+        lib.version = "1.0".into();
+        lib.statik = statik;
+        lib.libs = vec![InternalLib::new(name.into(), true)];
+        lib
+    }
+
+    #[test]
+    fn spdx_relationships() {
+        let mut libraries = Dependencies::default();
+        libraries.add("foo-bar", library("foo", true));
+        libraries.add("foo_bar", library("bar", false));
+
+        let doc = generate(&libraries, SbomFormat::Spdx, "foo-sys", "0.1.0");
+
+        // The crate links the libraries, not the other way around
+        assert!(doc.contains(
+            r#"{
+      "spdxElementId": "SPDXRef-Package-foo-sys",
+      "relationshipType": "STATIC_LINK",
+      "relatedSpdxElement": "SPDXRef-Package-system-deps-foo-bar"
+    }"#
+        ));
+        // Keys mapping to the same id are disambiguated
+        assert!(doc.contains(
+            r#"{
+      "spdxElementId": "SPDXRef-Package-foo-sys",
+      "relationshipType": "DYNAMIC_LINK",
+      "relatedSpdxElement": "SPDXRef-Package-system-deps-foo-bar-2"
+    }"#
+        ));
+    }
+
+    #[test]
+    fn static_archive_not_available() {
+        // `foo` has been requested static but is linked dynamically as there is no archive
+        let mut foo = library("foo", true);
+        foo.libs = vec![InternalLib::new("foo".into(), false)];
+        let mut bar = library("bar", true);
+        bar.libs.push(InternalLib::new("bar-dyn".into(), false));
+
+        let mut libraries = Dependencies::default();
+        libraries.add("foo", foo);
+        libraries.add("bar", bar);
+
+        let doc = generate(&libraries, SbomFormat::Spdx, "foo-sys", "0.1.0");
+        assert!(doc.contains(
+            r#"{
+      "spdxElementId": "SPDXRef-Package-foo-sys",
+      "relationshipType": "DYNAMIC_LINK",
+      "relatedSpdxElement": "SPDXRef-Package-system-deps-foo"
+    }"#
+        ));
+        assert!(!doc.contains(
+            r#""relationshipType": "STATIC_LINK",
+      "relatedSpdxElement": "SPDXRef-Package-system-deps-foo""#
+        ));
+        // `bar` is partly linked statically
+        for relationship in ["STATIC_LINK", "DYNAMIC_LINK"] {
+            assert!(doc.contains(&format!(
+                r#""relationshipType": "{}",
+      "relatedSpdxElement": "SPDXRef-Package-system-deps-bar""#,
+                relationship
+            )));
+        }
+
+        let doc = generate(&libraries, SbomFormat::CycloneDx, "foo-sys", "0.1.0");
+        assert!(doc.contains(
+            r#"{
+          "name": "system-deps:key",
+          "value": "foo"
+        },
+        {
+          "name": "system-deps:linkage",
+          "value": "dynamic"
+        }"#
+        ));
+        assert!(doc.contains(
+            r#"{
+          "name": "system-deps:linkage",
+          "value": "mixed"
+        },
+        {
+          "name": "system-deps:static-libs",
+          "value": "bar"
+        }"#
+        ));
+    }
+}
@@ -4,31 +4,32 @@ use std::collections::HashMap;
 use std::env;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::Mutex;
 
 use assert_matches::assert_matches;
 
 use crate::Dependencies;
 
 use super::{
-    BuildFlags, BuildInternalClosureError, Config, EnvVariables, Error, InternalLib, Library,
+    BuildFlag, BuildFlags, BuildInternalClosureError, Config, EnvSource, Error, FakeBackend,
+    InternalLib, InternalPkgConfig, Library, MetadataError, MetadataLocation, MetadataWarning,
+    ProbeBackend, ProbeErrorKind, Source,
 };
 
-lazy_static! {
-    static ref LOCK: Mutex<()> = Mutex::new(());
+fn test_dir() -> PathBuf {
+    env::current_dir().unwrap().join("src").join("tests")
 }
 
 fn create_config(path: &str, env: Vec<(&'static str, &'static str)>) -> Config {
-    {
-        // PKG_CONFIG_PATH is read by pkg-config, so we need to actually change the env
-        let _l = LOCK.lock();
-        env::set_var(
-            "PKG_CONFIG_PATH",
-            env::current_dir().unwrap().join("src").join("tests"),
-        );
-    }
-
     let mut hash = HashMap::new();
+    hash.insert(
+        "PKG_CONFIG_PATH",
+        env::current_dir()
+            .unwrap()
+            .join("src")
+            .join("tests")
+            .to_string_lossy()
+            .to_string(),
+    );
     hash.insert(
         "CARGO_MANIFEST_DIR",
         env::current_dir()
@@ -44,8 +45,12 @@ fn create_config(path: &str, env: Vec<(&'static str, &'static str)>) -> Config {
     env.iter().for_each(|(k, v)| {
         hash.insert(k, v.to_string());
     });
+    // Tests setting the target are not cross-compiling, unless the host is set as well
+    if let Some(target) = hash.get("TARGET").cloned() {
+        hash.entry("HOST").or_insert(target);
+    }
 
-    Config::new_with_env(EnvVariables::Mock(hash))
+    Config::new().env_source(hash)
 }
 
 fn toml(
@@ -58,8 +63,11 @@ fn toml(
 }
 
 fn assert_flags(flags: BuildFlags, expected: &str) {
+    // .pc files are found in PKG_CONFIG_PATH, compare their path relative to the current dir
+    let cwd = format!("{}/", env::current_dir().unwrap().display());
+    let flags = flags.to_string().replace(&cwd, "");
     // flags ordering isn't guaranteed so sort them out before comparing
-    let flags = flags.to_string().split('\n').sorted().join("\n");
+    let flags = flags.split('\n').sorted().join("\n");
     let expected = expected.split('\n').sorted().join("\n");
     assert_eq!(flags, expected);
 }
@@ -103,6 +111,15 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -134,7 +151,13 @@ fn version_range() {
 fn version_range_unsatisfied() {
     let err = toml_err("toml-version-range-unsatisfied");
 
-    assert_matches!(err, Error::PkgConfig(_));
+    assert_matches!(
+        err,
+        Error::PkgConfig {
+            kind: ProbeErrorKind::WrongVersion,
+            ..
+        }
+    );
 
     let err_msg = err.to_string();
     // pkgconf and pkg-config give different error messages
@@ -145,13 +168,65 @@ fn version_range_unsatisfied() {
     }
 }
 
+#[test]
+fn missing_multiple() {
+    let err = toml_err("toml-missing-multiple");
+
+    let missing = match &err {
+        Error::MultipleMissing(missing) => missing,
+        _ => panic!("unexpected error: {:?}", err),
+    };
+    assert_eq!(missing.len(), 2);
+
+    assert_eq!(missing[0].key, "nosuchlib");
+    assert_eq!(
+        missing[0].names,
+        vec![
+            ("nosuchlib".to_string(), "1.2".to_string()),
+            ("nosuchlib-1.0".to_string(), "1.2".to_string())
+        ]
+    );
+    assert_eq!(missing[0].feature, None);
+
+    assert_eq!(missing[1].key, "testdata");
+    assert_eq!(
+        missing[1].names,
+        vec![("testdata".to_string(), "99".to_string())]
+    );
+    assert_eq!(missing[1].feature.as_deref(), Some("test-feature"));
+
+    // the kind of failure is found by probing again without the version constraint
+    assert_matches!(
+        missing[0].error,
+        Error::PkgConfig { key: Some(ref key), kind: ProbeErrorKind::NotFound, .. }
+            if key == "nosuchlib"
+    );
+    assert_matches!(
+        missing[1].error,
+        Error::PkgConfig { key: Some(ref key), kind: ProbeErrorKind::WrongVersion, .. }
+            if key == "testdata"
+    );
+
+    let pkg_config_path = env::current_dir().unwrap().join("src").join("tests");
+    assert_eq!(
+        err.to_string(),
+        format!(
+            r#"2 system dependencies have not been found:
+- nosuchlib: tried `nosuchlib`, `nosuchlib-1.0` with version 1.2
+- testdata: tried `testdata` with version 99 (required by feature `test-feature`)
+PKG_CONFIG_PATH: {}"#,
+            pkg_config_path.display()
+        )
+    );
+}
+
 fn toml_err(path: &str) -> Error {
     toml(path, vec![]).unwrap_err()
 }
 
 fn toml_err_invalid(path: &str, err_ends_with: &str) {
     let err = toml_err(path);
-    assert_matches!(err, Error::InvalidMetadata(_));
+    assert_matches!(err, Error::InvalidMetadata { .. });
 
     if !err.to_string().ends_with(err_ends_with) {
         panic!(
@@ -161,6 +236,24 @@ fn toml_err_invalid(path: &str, err_ends_with: &str) {
     }
 }
 
+// Assert an error while parsing the manifest, `err_ends_with` being the end of the metadata error
+fn toml_err_manifest(path: &str, err_ends_with: &str) -> Option<MetadataLocation> {
+    match toml_err(path) {
+        Error::InvalidManifest {
+            error: e, location, ..
+        } => {
+            if !e.to_string().ends_with(err_ends_with) {
+                panic!(
+                    "Expected error to end with: {:?}\nGot error: {:?}",
+                    err_ends_with, e
+                );
+            }
+            location
+        }
+        err => panic!("Wrong error type: {:?}", err),
+    }
+}
+
 // Assert a PkgConfig error because requested lib version cannot be found
 fn toml_pkg_config_err_version(
     path: &str,
@@ -169,15 +262,15 @@ fn toml_pkg_config_err_version(
 ) {
     let err = toml(path, env_vars).unwrap_err();
     match err {
-        Error::PkgConfig(e) => match e {
+        Error::PkgConfig { cause, .. } => match cause {
             pkg_config::Error::ProbeFailure {
                 command: cmd,
                 output: _,
                 name: _,
             } => {
                 let s = format!(">= {}", expected_version);
-                // remove trailing '"", if any
-                let cmd = cmd.strip_suffix('"').unwrap_or(&cmd);
+                // remove trailing quote, if any
+                let cmd = cmd.trim_end_matches(['"', '\'']);
                 assert!(cmd.ends_with(&s));
             }
             _ => panic!("Wrong pkg-config error type"),
@@ -193,7 +286,7 @@ fn missing_file() {
 
 #[test]
 fn missing_key() {
-    toml_err_invalid(
+    toml_err_manifest(
         "toml-missing-key",
         "missing key `package.metadata.system-deps`",
     );
@@ -201,7 +294,7 @@ fn missing_key() {
 
 #[test]
 fn not_table() {
-    toml_err_invalid(
+    toml_err_manifest(
         "toml-not-table",
         "`package.metadata.system-deps` is not a table",
     );
@@ -209,12 +302,12 @@ fn not_table() {
 
 #[test]
 fn version_missing() {
-    toml_err_invalid("toml-version-missing", "No version defined for testlib");
+    toml_err_invalid("toml-version-missing", "testlib: no version defined");
 }
 
 #[test]
 fn version_not_string() {
-    toml_err_invalid(
+    toml_err_manifest(
         "toml-version-not-string",
         "`package.metadata.system-deps.testlib`: not a string or a table",
     );
@@ -222,7 +315,7 @@ fn version_not_string() {
 
 #[test]
 fn version_in_table_not_string() {
-    toml_err_invalid(
+    toml_err_manifest(
         "toml-version-in-table-not-string",
         "metadata.system-deps.testlib: unexpected key version type integer",
     );
@@ -230,7 +323,7 @@ fn version_in_table_not_string() {
 
 #[test]
 fn feature_not_string() {
-    toml_err_invalid(
+    toml_err_manifest(
         "toml-feature-not-string",
         "metadata.system-deps.testlib: unexpected key feature type integer",
     );
@@ -238,24 +331,77 @@ fn feature_not_string() {
 
 #[test]
 fn unexpected_key() {
-    toml_err_invalid(
+    toml_err_manifest(
         "toml-unexpected-key",
         "metadata.system-deps.testlib: unexpected key color type string",
     );
 }
 
+#[test]
+fn manifest_error_location() {
+    let err = toml_err("toml-unexpected-key");
+    let location = match &err {
+        Error::InvalidManifest {
+            error: MetadataError::UnexpectedKey { key, setting, .. },
+            location,
+            ..
+        } => {
+            assert_eq!(key, "package.metadata.system-deps.testlib");
+            assert_eq!(setting, "color");
+            location.clone().unwrap()
+        }
+        err => panic!("Wrong error type: {:?}", err),
+    };
+    assert_eq!((location.line, location.column), (2, 36));
+    assert_eq!(
+        location.snippet,
+        r#"  |
+2 | testlib = { version = "1", color = "blue" }
+  |                                    ^^^^^^"#
+    );
+    assert!(err.to_string().ends_with(&format!(
+        "toml-unexpected-key{}Cargo.toml:2:36: package.metadata.system-deps.testlib: unexpected key color type string\n{}",
+        std::path::MAIN_SEPARATOR,
+        location.snippet
+    )));
+
+    // Values in nested tables and arrays
+    let location = toml_err_manifest(
+        "toml-nested-error",
+        "`package.metadata.system-deps.cfg(unix).testlib.fallback-names[1]`: not a string",
+    )
+    .unwrap();
+    assert_eq!((location.line, location.column), (6, 32));
+
+    // TOML syntax errors
+    let err = toml_err("toml-invalid-syntax");
+    assert_matches!(
+        err,
+        Error::InvalidManifest {
+            error: MetadataError::Toml { .. },
+            location: Some(MetadataLocation { line: 2, .. }),
+            ..
+        }
+    );
+}
+
 #[test]
 fn override_name() {
     let (libraries, _) = toml("toml-override-name", vec![]).unwrap();
     let testlib = libraries.get_by_name("test_lib").unwrap();
     assert_eq!(testlib.name, "testlib");
     assert_eq!(testlib.version, "1.2.3");
+    assert_eq!(testlib.requested_name, "testlib");
+    assert_eq!(testlib.required_version, "1.0");
+    assert_eq!(testlib.version_override, None);
 
     // Enable feature 1.2
     let (libraries, _) = toml("toml-override-name", vec![("CARGO_FEATURE_V1_2", "")]).unwrap();
     let testlib = libraries.get_by_name("test_lib").unwrap();
     assert_eq!(testlib.name, "testlib");
     assert_eq!(testlib.version, "1.2.3");
+    assert_eq!(testlib.required_version, "1.2");
+    assert_eq!(testlib.version_override.as_deref(), Some("v1_2"));
 }
 
 #[test]
@@ -264,6 +410,9 @@ fn fallback_names() {
     let testlib = libraries.get_by_name("test_lib").unwrap();
     assert_eq!(testlib.name, "testlib");
     assert_eq!(testlib.version, "1.2.3");
+    assert_eq!(testlib.requested_name, "nosuchlib");
+    assert_eq!(testlib.required_version, "1.0");
+    assert_eq!(testlib.version_override, None);
 
     eprintln!();
     eprintln!("{flags}");
@@ -275,6 +424,14 @@ cargo:rustc-link-lib=test
 cargo:rustc-link-lib=framework=someframework
 cargo:include=/usr/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_LINK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TEST_LIB_LIB
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TEST_LIB_LIB_FRAMEWORK
@@ -303,6 +460,14 @@ cargo:rustc-link-lib=test
 cargo:rustc-link-lib=framework=someframework
 cargo:include=/usr/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_LINK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TEST_LIB_LIB
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TEST_LIB_LIB_FRAMEWORK
@@ -325,6 +490,9 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TEST_LIB_LINK
     let testlib = libraries.get_by_name("test_lib").unwrap();
     assert_eq!(testlib.name, "testlib-2.0");
     assert_eq!(testlib.version, "2.0.0");
+    assert_eq!(testlib.requested_name, "nosuchlib");
+    assert_eq!(testlib.required_version, "2.0");
+    assert_eq!(testlib.version_override.as_deref(), Some("v2"));
 
     assert_flags(
         flags,
@@ -332,6 +500,14 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TEST_LIB_LINK
 cargo:rustc-link-lib=test
 cargo:include=/usr/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testlib-2.0.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_LINK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TEST_LIB_LIB
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TEST_LIB_LIB_FRAMEWORK
@@ -380,6 +556,148 @@ fn feature_versions() {
     assert_eq!(testlib.name, "testlib-3.0");
 }
 
+#[test]
+fn feature_overrides() {
+    // `test-feature` is always enabled by the tests
+    let (libraries, _) = toml("toml-overrides", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.required_version, "1.2");
+    assert_eq!(testlib.version_override.as_deref(), Some("test-feature"));
+    let testdata = libraries.get_by_name("testdata").unwrap();
+    assert_eq!(testdata.required_version, "4");
+    assert_eq!(testdata.version_override, None);
+
+    // `overrides` entries take precedence over `v*` keys requiring the same version
+    let (libraries, _) = toml(
+        "toml-overrides",
+        vec![
+            ("CARGO_FEATURE_V1_2", ""),
+            ("CARGO_FEATURE_ANOTHER_FEATURE", ""),
+        ],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.name, "testlib");
+    assert_eq!(testlib.version_override.as_deref(), Some("test-feature"));
+    let testdata = libraries.get_by_name("testdata").unwrap();
+    assert_eq!(testdata.required_version, "4.5");
+    assert_eq!(
+        testdata.version_override.as_deref(),
+        Some("another-feature")
+    );
+
+    // Higher version making the dependency optional
+    let (libraries, _) = toml("toml-overrides", vec![("CARGO_FEATURE_OTHER_FEATURE", "")]).unwrap();
+    assert!(libraries.get_by_name("testlib").is_none());
+}
+
+#[test]
+fn override_ranges() {
+    // The required version matches all the enabled overrides
+    let (libraries, _) = toml(
+        "toml-override-ranges",
+        vec![("CARGO_FEATURE_V1_1", ""), ("CARGO_FEATURE_V1_2", "")],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.required_version, ">= 1.2, < 2");
+    assert_eq!(testlib.version_override.as_deref(), Some("v1_2"));
+
+    let (libraries, _) = toml("toml-override-ranges", vec![("CARGO_FEATURE_V1_1", "")]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.required_version, ">= 1.1, < 2");
+
+    let err = toml(
+        "toml-override-ranges",
+        vec![("CARGO_FEATURE_V1_1", ""), ("CARGO_FEATURE_V3", "")],
+    )
+    .unwrap_err();
+    assert_matches!(err, Error::InvalidMetadata { key: Some(ref key), .. } if key == "testlib");
+    assert_eq!(
+        err.to_string(),
+        "testlib: no version matches all of `>= 1.1, < 2`, `3`"
+    );
+}
+
+#[test]
+fn check_metadata() {
+    let warnings = create_config("toml-lint", vec![]).check_metadata().unwrap();
+    assert_eq!(
+        warnings,
+        vec![
+            MetadataWarning::UndeclaredFeature {
+                key: "testdata".into(),
+                feature: "foo".into()
+            },
+            MetadataWarning::EmptyVersionRange {
+                key: "testdata".into(),
+                version: ">= 4, < 3".into()
+            },
+            MetadataWarning::UndeclaredOverrideFeature {
+                key: "testlib".into(),
+                feature: "v1_6".into()
+            },
+            MetadataWarning::DecreasingOverrideVersion {
+                key: "testlib".into(),
+                feature: "v1_4".into(),
+                version: "1.3".into(),
+                previous_feature: "v1_2".into(),
+                previous_version: "1.4".into()
+            },
+            MetadataWarning::DuplicateFallbackName {
+                key: "testlib".into(),
+                name: "testlib".into()
+            },
+            MetadataWarning::DuplicateName {
+                name: "testlib".into(),
+                keys: vec!["other_testlib".into(), "testlib".into()]
+            },
+        ]
+    );
+    assert_eq!(
+        warnings[3].to_string(),
+        "testlib: override `v1_4` requires version `1.3`, lower than `1.4` required by `v1_2`"
+    );
+
+    // Warnings are emitted before the other directives
+    let libraries = create_config("toml-overrides", vec![]).resolve().unwrap();
+    assert_eq!(
+        libraries.warnings(),
+        &[
+            MetadataWarning::UndeclaredOverrideFeature {
+                key: "testdata".into(),
+                feature: "another-feature".into()
+            },
+            MetadataWarning::UndeclaredOverrideFeature {
+                key: "testlib".into(),
+                feature: "v1_2".into()
+            },
+            MetadataWarning::UndeclaredOverrideFeature {
+                key: "testlib".into(),
+                feature: "other-feature".into()
+            },
+            MetadataWarning::UndeclaredOverrideFeature {
+                key: "testlib".into(),
+                feature: "test-feature".into()
+            },
+        ]
+    );
+    let mut out = Vec::new();
+    libraries.emit_cargo_directives(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with(
+        "cargo:warning=testdata: override `another-feature` does not match any feature declared in [features]\n"
+    ));
+}
+
+#[test]
+fn vendor_key_not_an_override() {
+    toml_err_manifest(
+        "toml-vendor-key",
+        "metadata.system-deps.testlib: unexpected key vendor type table",
+    );
+}
+
 #[test]
 fn override_search_native() {
     #[cfg(target_os = "windows")]
@@ -421,6 +739,15 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -462,6 +789,15 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -510,6 +846,15 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -551,6 +896,15 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -592,6 +946,15 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -638,6 +1001,15 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -685,6 +1057,14 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -717,13 +1097,10 @@ fn test_build_internal(
     let config = create_config(path, env).add_build_internal(expected_lib, move |lib, version| {
         called_clone.replace(true);
         assert_eq!(lib, expected_lib);
-        let mut pkg_lib = pkg_config::Config::new()
-            .print_system_libs(false)
-            .cargo_metadata(false)
-            .probe(lib)
-            .unwrap();
+        let mut pkg_lib = Library::from_internal_pkg_config(test_dir(), lib, "0").unwrap();
         pkg_lib.version = version.to_string();
-        Ok(Library::from_pkg_config(lib, pkg_lib))
+        pkg_lib.statik = false;
+        Ok(pkg_lib)
     });
 
     match config.probe_full() {
@@ -789,7 +1166,11 @@ fn build_internal_auto_never() {
     )
     .unwrap_err();
 
-    assert!(matches!(err, Error::PkgConfig(..)));
+    assert_matches!(
+        err,
+        Error::PkgConfig { key: Some(ref key), ref version, kind: ProbeErrorKind::WrongVersion, .. }
+            if key == "testdata" && version == "5"
+    );
     assert!(!called);
 }
 
@@ -801,7 +1182,11 @@ fn build_internal_always_no_closure() {
     );
 
     let err = config.probe_full().unwrap_err();
-    assert!(matches!(err, Error::BuildInternalNoClosure(..)));
+    assert_matches!(
+        err,
+        Error::BuildInternalNoClosure { ref key, ref name, ref version }
+            if key == "testlib" && name == "testlib" && version == "1"
+    );
 }
 
 #[test]
@@ -830,16 +1215,17 @@ fn build_internal_wrong_version() {
     .add_build_internal("testdata", move |lib, _version| {
         called_clone.replace(true);
         assert_eq!(lib, "testdata");
-        let pkg_lib = pkg_config::Config::new()
-            .print_system_libs(false)
-            .cargo_metadata(false)
-            .probe(lib)
-            .unwrap();
-        Ok(Library::from_pkg_config(lib, pkg_lib))
+        let mut pkg_lib = Library::from_internal_pkg_config(test_dir(), lib, "0").unwrap();
+        pkg_lib.statik = false;
+        Ok(pkg_lib)
     });
 
     let err = config.probe_full().unwrap_err();
-    assert!(matches!(err, Error::BuildInternalWrongVersion(..)));
+    assert_matches!(
+        err,
+        Error::BuildInternalWrongVersion { ref key, ref version, ref required_version, .. }
+            if key == "testdata" && version == "4.5.6" && required_version == "5"
+    );
     assert!(called.get());
 }
 
@@ -858,7 +1244,7 @@ fn build_internal_fail() {
     });
 
     let err = config.probe_full().unwrap_err();
-    assert!(matches!(err, Error::BuildInternalClosureError(..)));
+    assert!(matches!(err, Error::BuildInternalClosureError { ref name, .. } if name == "testlib"));
     assert!(called.get());
 }
 
@@ -871,24 +1257,18 @@ fn build_internal_always_global() {
         .add_build_internal("testlib", move |lib, version| {
             let (_, b) = called_clone.get();
             called_clone.replace((true, b));
-            let mut pkg_lib = pkg_config::Config::new()
-                .print_system_libs(false)
-                .cargo_metadata(false)
-                .probe(lib)
-                .unwrap();
+            let mut pkg_lib = Library::from_internal_pkg_config(test_dir(), lib, "0").unwrap();
             pkg_lib.version = version.to_string();
-            Ok(Library::from_pkg_config(lib, pkg_lib))
+            pkg_lib.statik = false;
+            Ok(pkg_lib)
         })
         .add_build_internal("testdata", move |lib, version| {
             let (a, _) = called_clone2.get();
             called_clone2.replace((a, true));
-            let mut pkg_lib = pkg_config::Config::new()
-                .print_system_libs(false)
-                .cargo_metadata(false)
-                .probe(lib)
-                .unwrap();
+            let mut pkg_lib = Library::from_internal_pkg_config(test_dir(), lib, "0").unwrap();
             pkg_lib.version = version.to_string();
-            Ok(Library::from_pkg_config(lib, pkg_lib))
+            pkg_lib.statik = false;
+            Ok(pkg_lib)
         });
 
     let libraries = config.probe_full().unwrap();
@@ -913,24 +1293,18 @@ fn build_internal_global_override() {
     .add_build_internal("testlib", move |lib, version| {
         let (_, b) = called_clone.get();
         called_clone.replace((true, b));
-        let mut pkg_lib = pkg_config::Config::new()
-            .print_system_libs(false)
-            .cargo_metadata(false)
-            .probe(lib)
-            .unwrap();
+        let mut pkg_lib = Library::from_internal_pkg_config(test_dir(), lib, "0").unwrap();
         pkg_lib.version = version.to_string();
-        Ok(Library::from_pkg_config(lib, pkg_lib))
+        pkg_lib.statik = false;
+        Ok(pkg_lib)
     })
     .add_build_internal("testdata", move |lib, version| {
         let (a, _) = called_clone2.get();
         called_clone2.replace((a, true));
-        let mut pkg_lib = pkg_config::Config::new()
-            .print_system_libs(false)
-            .cargo_metadata(false)
-            .probe(lib)
-            .unwrap();
+        let mut pkg_lib = Library::from_internal_pkg_config(test_dir(), lib, "0").unwrap();
         pkg_lib.version = version.to_string();
-        Ok(Library::from_pkg_config(lib, pkg_lib))
+        pkg_lib.statik = false;
+        Ok(pkg_lib)
     });
 
     let libraries = config.probe_full().unwrap();
@@ -964,8 +1338,22 @@ fn optional() {
     assert!(libs.get_by_name("testmore").is_none());
     assert!(libs.get_by_name("testbadger").is_none());
 
-    // testlib is no longer optional if enabling v5
-    toml_pkg_config_err_version("toml-optional", "5.0", vec![("CARGO_FEATURE_V5", "")]);
+    // testlib is no longer optional if enabling v5, testmore is still missing as well
+    let err = toml("toml-optional", vec![("CARGO_FEATURE_V5", "")]).unwrap_err();
+    match err {
+        Error::MultipleMissing(missing) => {
+            assert_eq!(missing.len(), 2);
+            assert_eq!(missing[0].key, "testlib");
+            assert_eq!(
+                missing[0].names,
+                vec![("testlib-5.0".to_string(), "5.0".to_string())]
+            );
+            assert_eq!(missing[0].feature.as_deref(), Some("v5"));
+            assert_eq!(missing[1].key, "testmore");
+            assert_eq!(missing[1].names[0].1, "2");
+        }
+        _ => panic!("Wrong error type: {:?}", err),
+    }
 }
 
 #[test]
@@ -1028,6 +1416,355 @@ fn os_specific() {
     assert!(libraries.get_by_name("testanotherlib").is_none());
 }
 
+#[test]
+fn cfg_feature() {
+    let (libraries, _) = toml(
+        "toml-cfg-feature",
+        vec![("TARGET", "x86_64-unknown-linux-gnu")],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_some());
+    assert!(libraries.get_by_name("testanotherlib").is_none());
+
+    let (libraries, _) = toml(
+        "toml-cfg-feature",
+        vec![
+            ("TARGET", "x86_64-apple-darwin"),
+            ("CARGO_FEATURE_ANOTHER_TEST_FEATURE", ""),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_none());
+    assert!(libraries.get_by_name("testanotherlib").is_some());
+}
+
+#[test]
+fn cargo_cfg() {
+    let (libraries, _) = toml(
+        "toml-cargo-cfg",
+        vec![("TARGET", "x86_64-unknown-linux-musl")],
+    )
+    .unwrap();
+    assert!(libraries.iter().is_empty());
+
+    let (libraries, _) = toml(
+        "toml-cargo-cfg",
+        vec![
+            ("TARGET", "x86_64-unknown-linux-musl"),
+            ("CARGO_CFG_TARGET_FEATURE", "crt-static,sse,sse2"),
+            ("CARGO_CFG_BADGER", ""),
+            ("CARGO_CFG_ANIMAL", "cat,badger"),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("teststaticlib").is_some());
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    let (libraries, _) = toml(
+        "toml-cargo-cfg",
+        vec![
+            ("TARGET", "x86_64-apple-darwin"),
+            ("CARGO_CFG_TARGET_FEATURE", "sse,sse2"),
+            ("CARGO_CFG_ANIMAL", "badger"),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.iter().is_empty());
+}
+
+#[test]
+fn cfg_nested() {
+    let (libraries, _) = toml(
+        "toml-cfg-nested",
+        vec![("TARGET", "x86_64-unknown-linux-gnu")],
+    )
+    .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_some());
+
+    let (libraries, _) = toml("toml-cfg-nested", vec![("TARGET", "x86_64-apple-darwin")]).unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(libraries.get_by_name("testlib").is_none());
+}
+
+#[test]
+fn cfg_override() {
+    let (libraries, _) = toml(
+        "toml-cfg-override",
+        vec![("TARGET", "x86_64-unknown-linux-gnu")],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("test_lib").unwrap();
+    assert_eq!(testlib.name, "testlib");
+    assert_eq!(testlib.version, "1.2.3");
+
+    let (libraries, _) =
+        toml("toml-cfg-override", vec![("TARGET", "x86_64-apple-darwin")]).unwrap();
+    let testlib = libraries.get_by_name("test_lib").unwrap();
+    assert_eq!(testlib.name, "testlib-2.0");
+    assert_eq!(testlib.required_version, "2.0");
+
+    let (libraries, _) = toml(
+        "toml-cfg-override",
+        vec![("TARGET", "x86_64-pc-windows-gnu")],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("test_lib").unwrap();
+    assert_eq!(testlib.requested_name, "testlib");
+    assert_eq!(testlib.name, "testlib-3.0");
+
+    // feature versions take precedence
+    let (libraries, _) = toml(
+        "toml-cfg-override",
+        vec![("TARGET", "x86_64-apple-darwin"), ("CARGO_FEATURE_V3", "")],
+    )
+    .unwrap();
+    let testlib = libraries.get_by_name("test_lib").unwrap();
+    assert_eq!(testlib.name, "testlib-3.0");
+    assert_eq!(testlib.version_override.as_deref(), Some("v3"));
+}
+
+#[test]
+fn any_of() {
+    let (libraries, _) = toml("toml-any-of", vec![]).unwrap();
+    let lib = libraries.get_by_name("test_data").unwrap();
+    assert_eq!(lib.name, "testdata");
+    assert_eq!(lib.version, "4.5.6");
+    assert_eq!(lib.alternative.as_deref(), Some("testdata"));
+
+    let (libraries, _) = toml(
+        "toml-any-of",
+        vec![("CARGO_FEATURE_ANOTHER_TEST_FEATURE", "")],
+    )
+    .unwrap();
+    let lib = libraries.get_by_name("test_data").unwrap();
+    assert_eq!(lib.name, "testlib");
+    assert_eq!(lib.required_version, "1.2");
+    assert_eq!(lib.alternative.as_deref(), Some("testlib"));
+
+    let err = toml_err("toml-any-of-missing");
+    let missing = match &err {
+        Error::MultipleMissing(missing) => missing,
+        _ => panic!("unexpected error: {:?}", err),
+    };
+    assert_eq!(missing[1].key, "test_data");
+    assert_eq!(
+        missing[1].names,
+        vec![
+            ("nosuchlib".to_string(), "1".to_string()),
+            ("nosuchlib1".to_string(), "1".to_string()),
+            ("testdata".to_string(), "5".to_string()),
+        ]
+    );
+    assert!(err.to_string().contains(
+        "- test_data: tried `nosuchlib` (version 1), `nosuchlib1` (version 1), `testdata` (version 5)\n"
+    ));
+
+    // A required group must have at least one enabled alternative
+    assert_matches!(
+        toml_err("toml-any-of-disabled"),
+        Error::InvalidMetadata { key: Some(key), .. } if key == "test_data"
+    );
+    toml(
+        "toml-any-of-disabled",
+        vec![("CARGO_FEATURE_ANOTHER_TEST_FEATURE", "")],
+    )
+    .unwrap();
+
+    toml_err_manifest(
+        "toml-any-of-name",
+        "package.metadata.system-deps.test_data: name cannot be used along with any-of",
+    );
+}
+
+#[test]
+fn cfgs_if_found() {
+    let (libraries, _) = toml("toml-cfgs-if-found", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.cfgs, vec!["have_testlib", "testlib_is_cool"]);
+    let testdata = libraries.get_by_name("testdata").unwrap();
+    assert!(testdata.cfgs.is_empty());
+}
+
+#[test]
+fn resolve_and_emit() {
+    let libraries = create_config("toml-cfgs-if-found", vec![])
+        .resolve()
+        .unwrap();
+
+    let mut flags = libraries.build_flags().unwrap();
+    let manifest = env::current_dir()
+        .unwrap()
+        .join("src")
+        .join("tests")
+        .join("toml-cfgs-if-found")
+        .join("Cargo.toml");
+    assert!(flags
+        .iter()
+        .any(|f| *f == BuildFlag::RerunIfChanged(manifest.to_string_lossy().to_string())));
+    let cfgs = flags
+        .iter()
+        .filter_map(|f| match f {
+            BuildFlag::Cfg(cfg) => Some(cfg.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        cfgs,
+        vec![
+            "system_deps_have_testdata",
+            "system_deps_have_testlib",
+            "have_testlib",
+            "testlib_is_cool"
+        ]
+    );
+    assert!(flags
+        .iter()
+        .any(|f| *f == BuildFlag::Env("SYSTEM_DEPS_TESTLIB_VERSION".into(), "1.2.3".into())));
+
+    // Build scripts can filter the directives before emitting them
+    flags.retain(|f| matches!(f, BuildFlag::Lib(..)));
+    assert_eq!(flags.to_string(), "cargo:rustc-link-lib=test\n");
+
+    let mut out = Vec::new();
+    libraries.emit_cargo_directives(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out, libraries.build_flags().unwrap().to_string());
+    assert!(out.contains("cargo:rustc-cfg=system_deps_have_testlib\n"));
+    assert!(out.contains("cargo:rustc-env=SYSTEM_DEPS_TESTDATA_VERSION=4.5.6\n"));
+}
+
+#[test]
+fn add_dependency() {
+    use crate::DependencySpec;
+
+    // Replace testlib and add testanotherlib
+    let libraries = create_config("toml-good", vec![("TARGET", "x86_64-unknown-linux-gnu")])
+        .add_dependency(
+            "testlib",
+            DependencySpec {
+                name: Some("testlib-2.0".into()),
+                version: Some("2".into()),
+                ..Default::default()
+            },
+        )
+        .add_dependency(
+            "testanotherlib",
+            DependencySpec {
+                version: Some("1".into()),
+                cfg: Some("cfg(unix)".into()),
+                ..Default::default()
+            },
+        )
+        .add_dependency(
+            "nosuchlib",
+            DependencySpec {
+                version: Some("1".into()),
+                optional: true,
+                ..Default::default()
+            },
+        )
+        .probe_full()
+        .unwrap();
+
+    assert_eq!(
+        libraries
+            .iter()
+            .into_iter()
+            .map(|(key, lib)| (key, lib.name.as_str(), lib.version.as_str()))
+            .collect::<Vec<_>>(),
+        vec![
+            ("testanotherlib", "testanotherlib", "1.2.3"),
+            ("testdata", "testdata", "4.5.6"),
+            ("testlib", "testlib-2.0", "2.0.0"),
+        ]
+    );
+
+    // No metadata is needed
+    let libraries = create_config("toml-no-metadata", vec![])
+        .add_dependency(
+            "testdata",
+            DependencySpec {
+                version: Some("4".into()),
+                feature: Some("test-feature".into()),
+                ..Default::default()
+            },
+        )
+        .probe_full()
+        .unwrap();
+    assert!(libraries.get_by_name("testdata").is_some());
+
+    let err = create_config("toml-no-metadata", vec![])
+        .add_dependency(
+            "testdata",
+            DependencySpec {
+                version: Some("4".into()),
+                cfg: Some("cfg(unix".into()),
+                ..Default::default()
+            },
+        )
+        .probe_full()
+        .unwrap_err();
+    assert_matches!(
+        err,
+        Error::InvalidManifest { error: MetadataError::CfgExpr { ref key, .. }, location: None, .. }
+            if key == "testdata"
+    );
+}
+
+#[test]
+fn manifest_path() {
+    let (libraries, flags) = {
+        let libs = create_config("toml-good", vec![])
+            .manifest_path(
+                Path::new("src")
+                    .join("tests")
+                    .join("toml-custom-table")
+                    .join("Cargo.toml"),
+            )
+            .metadata_table("native-deps")
+            .probe_full()
+            .unwrap();
+        let flags = libs.build_flags().unwrap();
+        (libs, flags)
+    };
+    assert_eq!(libraries.iter().len(), 1);
+    assert!(libraries.get_by_name("testdata").is_some());
+    assert!(flags.to_string().contains(&format!(
+        "cargo:rerun-if-changed={}\n",
+        Path::new("src")
+            .join("tests")
+            .join("toml-custom-table")
+            .join("Cargo.toml")
+            .display()
+    )));
+}
+
+#[test]
+fn workspace_inheritance() {
+    let (libraries, _) = toml("toml-workspace/member", vec![]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.name, "testlib");
+    assert_eq!(testlib.required_version, "1");
+    let testdata = libraries.get_by_name("test_data").unwrap();
+    assert_eq!(testdata.name, "testdata");
+    assert_eq!(testdata.version, "4.5.6");
+    assert!(libraries.get_by_name("testanotherlib").is_some());
+
+    // Feature versions are inherited as well
+    let (libraries, _) = toml("toml-workspace/member", vec![("CARGO_FEATURE_V2", "")]).unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.name, "testlib-2.0");
+
+    toml_err_manifest(
+        "toml-workspace/member-missing",
+        "missing key `workspace.metadata.system-deps.nosuchlib`",
+    );
+}
+
 #[test]
 fn invalid_cfg() {
     let err = toml(
@@ -1061,6 +1798,15 @@ cargo:rustc-link-lib=static=teststatic
 cargo:rustc-link-lib=framework=someframework
 cargo:include=./src/tests/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/teststaticlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_LINK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTSTATICLIB_LIB
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTSTATICLIB_LIB_FRAMEWORK
@@ -1126,6 +1872,14 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTSTATICLIB_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTSTATICLIB_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTSTATICLIB_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTSTATICLIB_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -1153,6 +1907,15 @@ cargo:rustc-link-lib=static=teststatic
 cargo:rustc-link-lib=framework=someframework
 cargo:include=./src/tests/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/teststaticlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_LINK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTSTATICLIB_LIB
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTSTATICLIB_LIB_FRAMEWORK
@@ -1196,6 +1959,15 @@ cargo:rustc-link-lib=test
 cargo:rustc-link-lib=framework=someframework
 cargo:include=/usr/include/testlib
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/testlib.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_LINK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LIB_FRAMEWORK
@@ -1259,6 +2031,15 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIBWITHRPATH_NO_PKG_CONFIG
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIBWITHRPATH_SEARCH_FRAMEWORK
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIBWITHRPATH_SEARCH_NATIVE
 cargo:rerun-if-env-changed=SYSTEM_DEPS_BUILD_INTERNAL
+cargo:rerun-if-env-changed=SYSTEM_DEPS_LOCKED
+cargo:rerun-if-env-changed=SYSTEM_DEPS_UPDATE_LOCK
+cargo:rerun-if-env-changed=SYSTEM_DEPS_PROBE_CACHE
+cargo:rerun-if-changed=src/tests/testdata.pc
+cargo:rerun-if-changed=src/tests/testlibwithrpath.pc
+cargo:rerun-if-env-changed=PKG_CONFIG
+cargo:rerun-if-env-changed=PKG_CONFIG_PATH
+cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR
+cargo:rerun-if-env-changed=PKG_CONFIG_SYSROOT_DIR
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTLIBWITHRPATH_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_BUILD_INTERNAL
 cargo:rerun-if-env-changed=SYSTEM_DEPS_TESTDATA_LINK
@@ -1267,3 +2048,738 @@ cargo:rerun-if-env-changed=SYSTEM_DEPS_LINK
 "#,
     );
 }
+
+#[test]
+fn rerun_if_pc_files_changed() {
+    let (libraries, flags) = toml("toml-requires", vec![]).unwrap();
+    let testrequires = libraries.get_by_name("testrequires").unwrap();
+    let tests_dir = env::current_dir().unwrap().join("src").join("tests");
+    // testdata is a private requirement so it's only tracked when linking statically
+    assert_eq!(
+        testrequires.pc_files,
+        vec![
+            tests_dir.join("testrequires.pc"),
+            tests_dir.join("testlib.pc"),
+        ]
+    );
+
+    let flags = flags.to_string();
+    for pc_file in &testrequires.pc_files {
+        assert!(flags.contains(&format!("cargo:rerun-if-changed={}\n", pc_file.display())));
+    }
+    assert!(!flags.contains("cargo:rerun-if-changed=/usr/lib/\n"));
+
+    let (libraries, _) = toml("toml-requires", vec![("SYSTEM_DEPS_LINK", "static")]).unwrap();
+    let testrequires = libraries.get_by_name("testrequires").unwrap();
+    assert_eq!(
+        testrequires.pc_files,
+        vec![
+            tests_dir.join("testrequires.pc"),
+            tests_dir.join("testlib.pc"),
+            tests_dir.join("testdata.pc"),
+        ]
+    );
+}
+
+#[test]
+fn rerun_if_target_pkg_config_env_changed() {
+    let (_, flags) = toml(
+        "toml-good",
+        vec![
+            ("TARGET", "aarch64-unknown-linux-gnu"),
+            ("HOST", "x86_64-unknown-linux-gnu"),
+            ("PKG_CONFIG_ALLOW_CROSS", "1"),
+        ],
+    )
+    .unwrap();
+
+    let flags = flags.to_string();
+    for var in [
+        "PKG_CONFIG_PATH_aarch64-unknown-linux-gnu",
+        "PKG_CONFIG_PATH_aarch64_unknown_linux_gnu",
+        "TARGET_PKG_CONFIG_PATH",
+        "PKG_CONFIG_PATH",
+        "TARGET_PKG_CONFIG",
+        "TARGET_PKG_CONFIG_LIBDIR",
+        "TARGET_PKG_CONFIG_SYSROOT_DIR",
+    ] {
+        assert!(flags.contains(&format!("cargo:rerun-if-env-changed={}\n", var)));
+    }
+    assert!(!flags.contains("HOST_PKG_CONFIG"));
+}
+
+#[test]
+fn rerun_if_link_paths_changed() {
+    let libraries = create_config("toml-requires", vec![])
+        .rerun_if_link_paths_changed(true)
+        .probe_full()
+        .unwrap();
+    let flags = libraries.gen_flags().unwrap().to_string();
+    assert!(flags.contains("cargo:rerun-if-changed=/usr/lib/\n"));
+}
+
+#[test]
+fn lockfile_locked() {
+    let (_, flags) = toml(
+        "toml-lock",
+        vec![("SYSTEM_DEPS_LOCKED", "1"), ("CARGO_PKG_NAME", "toml-lock")],
+    )
+    .unwrap();
+    let flags = flags.to_string();
+    assert!(flags.contains("toml-lock/system-deps.lock\n"));
+}
+
+#[test]
+fn lockfile_mismatch() {
+    let err = toml(
+        "toml-lock-mismatch",
+        vec![
+            ("SYSTEM_DEPS_LOCKED", "1"),
+            ("CARGO_PKG_NAME", "toml-lock-mismatch"),
+        ],
+    )
+    .unwrap_err();
+    assert_matches!(err, Error::LockfileMismatch(_));
+    assert!(err
+        .to_string()
+        .ends_with("testlib has version 1.2.3 but 1.2.2 is locked"));
+
+    // The lock file has no entry for this crate
+    let err = toml(
+        "toml-lock",
+        vec![("SYSTEM_DEPS_LOCKED", "1"), ("CARGO_PKG_NAME", "badger")],
+    )
+    .unwrap_err();
+    assert_matches!(err, Error::InvalidLockfile(_));
+}
+
+#[test]
+fn lockfile_locked_disabled() {
+    // The lock file has no entry for this crate, but it is not checked
+    toml(
+        "toml-lock",
+        vec![("SYSTEM_DEPS_LOCKED", "0"), ("CARGO_PKG_NAME", "badger")],
+    )
+    .unwrap();
+
+    let err = toml(
+        "toml-lock",
+        vec![
+            ("SYSTEM_DEPS_LOCKED", "maybe"),
+            ("CARGO_PKG_NAME", "badger"),
+        ],
+    )
+    .unwrap_err();
+    assert_matches!(err, Error::InvalidEnvVariable { name, value } if name == "SYSTEM_DEPS_LOCKED" && value == "maybe");
+
+    let err = toml("toml-lock", vec![("SYSTEM_DEPS_LOCKED", "true")]).unwrap_err();
+    assert_matches!(err, Error::MissingEnvVariable(var) if var == "CARGO_PKG_NAME");
+}
+
+#[cfg(feature = "sbom")]
+#[test]
+fn sbom() {
+    use crate::SbomFormat;
+
+    let (libraries, _) = toml(
+        "toml-static",
+        vec![("SYSTEM_DEPS_TESTSTATICLIB_LINK", "static")],
+    )
+    .unwrap();
+
+    // paths are JSON-escaped
+    let pc_file = |name: &str| {
+        env::current_dir()
+            .unwrap()
+            .join("src")
+            .join("tests")
+            .join(name)
+            .display()
+            .to_string()
+            .replace('\\', "\\\\")
+    };
+
+    assert_eq!(
+        libraries.sbom(SbomFormat::Spdx, "foo-sys", "0.1.0"),
+        format!(
+            r#"{{
+  "spdxVersion": "SPDX-2.3",
+  "dataLicense": "CC0-1.0",
+  "SPDXID": "SPDXRef-DOCUMENT",
+  "name": "foo-sys-0.1.0-system-deps",
+  "documentNamespace": "https://spdx.org/spdxdocs/foo-sys-0.1.0-system-deps",
+  "creationInfo": {{
+    "created": "1970-01-01T00:00:00Z",
+    "creators": [
+      "Tool: system-deps-{}"
+    ]
+  }},
+  "packages": [
+    {{
+      "SPDXID": "SPDXRef-Package-foo-sys",
+      "name": "foo-sys",
+      "versionInfo": "0.1.0",
+      "downloadLocation": "NOASSERTION",
+      "filesAnalyzed": false
+    }},
+    {{
+      "SPDXID": "SPDXRef-Package-system-deps-testdata",
+      "name": "testdata",
+      "versionInfo": "4.5.6",
+      "downloadLocation": "NOASSERTION",
+      "filesAnalyzed": false,
+      "primaryPackagePurpose": "LIBRARY",
+      "sourceInfo": "resolved from {}",
+      "comment": "Dynamically linked system library"
+    }},
+    {{
+      "SPDXID": "SPDXRef-Package-system-deps-teststaticlib",
+      "name": "teststaticlib",
+      "versionInfo": "1.2.3",
+      "downloadLocation": "NOASSERTION",
+      "filesAnalyzed": false,
+      "primaryPackagePurpose": "LIBRARY",
+      "sourceInfo": "resolved from {}",
+      "comment": "Statically linked system library"
+    }}
+  ],
+  "relationships": [
+    {{
+      "spdxElementId": "SPDXRef-DOCUMENT",
+      "relationshipType": "DESCRIBES",
+      "relatedSpdxElement": "SPDXRef-Package-foo-sys"
+    }},
+    {{
+      "spdxElementId": "SPDXRef-Package-foo-sys",
+      "relationshipType": "DYNAMIC_LINK",
+      "relatedSpdxElement": "SPDXRef-Package-system-deps-testdata"
+    }},
+    {{
+      "spdxElementId": "SPDXRef-Package-foo-sys",
+      "relationshipType": "STATIC_LINK",
+      "relatedSpdxElement": "SPDXRef-Package-system-deps-teststaticlib"
+    }}
+  ]
+}}
+"#,
+            env!("CARGO_PKG_VERSION"),
+            pc_file("testdata.pc"),
+            pc_file("teststaticlib.pc")
+        )
+    );
+
+    let cdx = libraries.sbom(SbomFormat::CycloneDx, "foo-sys", "0.1.0");
+    assert!(cdx.contains(r#""bomFormat": "CycloneDX""#));
+    assert!(cdx.contains(&format!(r#""value": "{}""#, pc_file("teststaticlib.pc"))));
+    let testdata = cdx.find(r#""bom-ref": "system-deps:testdata""#).unwrap();
+    let teststaticlib = cdx
+        .find(r#""bom-ref": "system-deps:teststaticlib""#)
+        .unwrap();
+    assert!(testdata < teststaticlib);
+
+    // The SBOM is written to OUT_DIR when probing
+    let out_dir = env::temp_dir().join(format!("system-deps-sbom-{}", std::process::id()));
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let out_dir: &'static str = Box::leak(out_dir.to_string_lossy().into_owned().into_boxed_str());
+    toml(
+        "toml-static",
+        vec![
+            ("OUT_DIR", out_dir),
+            ("CARGO_PKG_NAME", "foo-sys"),
+            ("CARGO_PKG_VERSION", "0.1.0"),
+            ("SYSTEM_DEPS_TESTSTATICLIB_LINK", "static"),
+        ],
+    )
+    .unwrap();
+    for format in [SbomFormat::Spdx, SbomFormat::CycloneDx] {
+        let content = std::fs::read_to_string(Path::new(out_dir).join(format.file_name())).unwrap();
+        assert_eq!(content, libraries.sbom(format, "foo-sys", "0.1.0"));
+    }
+    std::fs::remove_dir_all(out_dir).unwrap();
+}
+
+#[test]
+fn fake_backend() {
+    let backend = FakeBackend::new()
+        .library("testlib", "1.5")
+        .library("testdata", "4.0");
+    let libraries = create_config("toml-good", vec![])
+        .probe_backend(backend)
+        .resolve()
+        .unwrap();
+
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.version, "1.5");
+    assert_eq!(testlib.libs[0].name, "testlib");
+    assert_eq!(testlib.source, Source::PkgConfig);
+    assert_eq!(libraries.get_by_name("testdata").unwrap().version, "4.0");
+
+    // version too old
+    let backend = FakeBackend::new()
+        .library("testlib", "0.9")
+        .library("testdata", "4.0");
+    let err = create_config("toml-good", vec![])
+        .probe_backend(backend)
+        .resolve()
+        .unwrap_err();
+    assert_matches!(
+        err,
+        Error::ProbeFailure { key: Some(ref key), ref name, ref version, kind: ProbeErrorKind::WrongVersion, ref cause }
+            if key == "testlib" && name == "testlib" && version == "1"
+                && cause == "Requested '1' but version of testlib is 0.9"
+    );
+
+    // not found
+    let backend = FakeBackend::new().library("testlib", "1.0");
+    let err = create_config("toml-good", vec![])
+        .probe_backend(backend)
+        .resolve()
+        .unwrap_err();
+    assert_matches!(
+        err,
+        Error::ProbeFailure { key: Some(ref key), kind: ProbeErrorKind::NotFound, .. } if key == "testdata"
+    );
+    assert_eq!(err.to_string(), "Package testdata was not found");
+}
+
+#[test]
+fn env_source() {
+    let mut env = HashMap::new();
+    env.insert("FOO".to_string(), "bar".to_string());
+    assert_eq!(env.var("FOO").as_deref(), Some("bar"));
+    assert_eq!(env.var("BAR"), None);
+
+    // Backend finding the libraries defined in the environment it is given
+    struct EnvBackend;
+
+    impl ProbeBackend for EnvBackend {
+        fn probe(
+            &self,
+            name: &str,
+            version: &str,
+            statik: bool,
+            env: &dyn EnvSource,
+        ) -> Result<Library, Error> {
+            let mut backend = FakeBackend::new();
+            if let Some(installed) = env.var(&format!("INSTALLED_{}", name.to_uppercase())) {
+                backend = backend.library(name, &installed);
+            }
+            backend.probe(name, version, statik, env)
+        }
+    }
+
+    // The variables are only defined in the injected environment
+    let libraries = create_config(
+        "toml-good",
+        vec![
+            ("INSTALLED_TESTLIB", "1.2.3"),
+            ("INSTALLED_TESTDATA", "4.5.6"),
+        ],
+    )
+    .probe_backend(EnvBackend)
+    .resolve()
+    .unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+    assert_eq!(libraries.get_by_name("testdata").unwrap().version, "4.5.6");
+
+    let err = create_config("toml-good", vec![("PKG_CONFIG_PATH", "/injected")])
+        .probe_backend(EnvBackend)
+        .resolve()
+        .unwrap_err();
+    let missing = match &err {
+        Error::MultipleMissing(missing) => missing,
+        _ => panic!("unexpected error: {:?}", err),
+    };
+    assert_eq!(missing[0].pkg_config_path.as_deref(), Some("/injected"));
+}
+
+#[test]
+fn default_backend_env_source() {
+    let process_path = env::var_os("PKG_CONFIG_PATH");
+
+    // PKG_CONFIG_PATH is only defined in the injected environment
+    let libraries = create_config("toml-good", vec![]).resolve().unwrap();
+    let testlib = libraries.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.version, "1.2.3");
+    assert_eq!(testlib.pc_files, vec![test_dir().join("testlib.pc")]);
+    assert_eq!(env::var_os("PKG_CONFIG_PATH"), process_path);
+
+    // and the failure kind is found using the same environment
+    let err = create_config("toml-good", vec![("PKG_CONFIG_PATH", "/injected")])
+        .resolve()
+        .unwrap_err();
+    let missing = match &err {
+        Error::MultipleMissing(missing) => missing,
+        _ => panic!("unexpected error: {:?}", err),
+    };
+    assert_matches!(
+        missing[0].error,
+        Error::PkgConfig {
+            kind: ProbeErrorKind::NotFound,
+            ..
+        }
+    );
+
+    // cross-compilation is only allowed if configured in the injected environment
+    let cross = vec![
+        ("TARGET", "aarch64-unknown-linux-gnu"),
+        ("HOST", "x86_64-unknown-linux-gnu"),
+    ];
+    let err = create_config("toml-good", cross.clone())
+        .resolve()
+        .unwrap_err();
+    let missing = match &err {
+        Error::MultipleMissing(missing) => missing,
+        _ => panic!("unexpected error: {:?}", err),
+    };
+    assert_matches!(
+        missing[0].error,
+        Error::PkgConfig {
+            cause: pkg_config::Error::CrossCompilation,
+            ..
+        }
+    );
+    let mut allowed = cross;
+    allowed.push(("PKG_CONFIG_ALLOW_CROSS", "1"));
+    create_config("toml-good", allowed).resolve().unwrap();
+}
+
+#[test]
+fn internal_pkg_config() {
+    let mut env = HashMap::new();
+    env.insert(
+        "PKG_CONFIG_PATH",
+        Path::new("src").join("tests").to_string_lossy().to_string(),
+    );
+    let process_path = env::var_os("PKG_CONFIG_PATH");
+
+    let lib = InternalPkgConfig::new()
+        .pc_dir(Path::new("src").join("tests").join("internal"))
+        .probe_with_env("testinternal", "2.0", &env)
+        .unwrap();
+    assert_eq!(lib.name, "testinternal");
+    assert_eq!(lib.version, "2.1.0");
+    assert!(lib.statik);
+    assert_eq!(
+        lib.pc_files,
+        vec![
+            Path::new("src/tests/internal/testinternal.pc").to_path_buf(),
+            Path::new("src/tests/testlib.pc").to_path_buf(),
+        ]
+    );
+    // The process environment is restored once pkg-config has run
+    assert_eq!(env::var_os("PKG_CONFIG_PATH"), process_path);
+
+    let lib = InternalPkgConfig::new()
+        .pc_dir(Path::new("src").join("tests").join("internal"))
+        .prefix("/opt/internal")
+        .rewrite_path("/opt/internal/include", "/sysroot/include")
+        .probe_with_env("testinternal", "2.0", &env)
+        .unwrap();
+    assert_eq!(
+        lib.include_paths,
+        vec![
+            Path::new("/sysroot/include/testinternal").to_path_buf(),
+            Path::new("/sysroot/include/testlib").to_path_buf(),
+        ]
+    );
+    assert_eq!(
+        lib.link_paths,
+        vec![
+            Path::new("/opt/internal/lib").to_path_buf(),
+            Path::new("/opt/internal/lib/").to_path_buf(),
+        ]
+    );
+
+    assert_matches!(
+        Library::from_internal_pkg_config(
+            Path::new("src").join("tests").join("internal"),
+            "testinternal",
+            "3.0"
+        ),
+        Err(BuildInternalClosureError::PkgConfig(_))
+    );
+}
+
+#[test]
+fn build_internal_with_context() {
+    // probe_full() writes the SBOM into OUT_DIR when the sbom feature is enabled
+    let out_dir = env::temp_dir().join(format!("system-deps-test-context-{}", std::process::id()));
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let out_dir_str: &'static str =
+        Box::leak(out_dir.to_string_lossy().into_owned().into_boxed_str());
+    let ctx_out_dir = out_dir.clone();
+
+    let called = Rc::new(Cell::new(false));
+    let called_clone = called.clone();
+    let config = create_config(
+        "toml-good",
+        vec![
+            ("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always"),
+            ("SYSTEM_DEPS_TESTLIB_LINK", "static"),
+            ("TARGET", "x86_64-unknown-linux-gnu"),
+            ("OUT_DIR", out_dir_str),
+        ],
+    )
+    .add_build_internal_with("testlib", move |ctx| {
+        called_clone.replace(true);
+        assert_eq!(ctx.key, "testlib");
+        assert_eq!(ctx.name, "testlib");
+        assert_eq!(ctx.version, "1");
+        assert_eq!(ctx.min_version, "1");
+        assert_eq!(ctx.max_version, None);
+        assert_eq!(ctx.target.as_deref(), Some("x86_64-unknown-linux-gnu"));
+        assert_eq!(ctx.out_dir, Some(ctx_out_dir));
+        assert!(ctx.statik);
+        // testdata is defined before testlib so has already been resolved
+        assert_eq!(
+            ctx.libraries.get_by_name("testdata").unwrap().version,
+            "4.5.6"
+        );
+
+        InternalPkgConfig::new().probe_with_env(&ctx.name, "0", ctx.env)
+    });
+
+    let libraries = config.probe_full().unwrap();
+    assert!(called.get());
+    assert!(libraries.get_by_name("testlib").unwrap().statik);
+    let _ = std::fs::remove_dir_all(&out_dir);
+}
+
+#[cfg(feature = "autotools")]
+#[test]
+fn build_internal_autotools() {
+    let out_dir =
+        env::temp_dir().join(format!("system-deps-test-autotools-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&out_dir);
+    let out_dir_str: &'static str =
+        Box::leak(out_dir.to_string_lossy().into_owned().into_boxed_str());
+
+    let libraries = create_config(
+        "toml-autotools",
+        vec![
+            ("SYSTEM_DEPS_TESTAUTOTOOLS_BUILD_INTERNAL", "auto"),
+            ("OUT_DIR", out_dir_str),
+        ],
+    )
+    .add_build_internal_with("testautotools", |ctx| {
+        crate::build_internal::autotools("src/tests/autotools").build(ctx)
+    })
+    .resolve()
+    .unwrap();
+
+    let lib = libraries.get_by_name("testautotools").unwrap();
+    let prefix = out_dir
+        .join("system-deps")
+        .join("testautotools")
+        .join("install");
+    assert_eq!(lib.version, "1.0.0");
+    assert_eq!(lib.link_paths, vec![prefix.join("lib")]);
+    assert_eq!(
+        lib.pc_files,
+        vec![prefix
+            .join("lib")
+            .join("pkgconfig")
+            .join("testautotools.pc")]
+    );
+    let _ = std::fs::remove_dir_all(&out_dir);
+}
+
+#[cfg(feature = "meson")]
+#[test]
+fn meson_cross_file() {
+    let target =
+        cfg_expr::targets::get_builtin_target_by_triple("aarch64-unknown-linux-gnu").unwrap();
+    assert_eq!(
+        crate::build_internal::meson_cross_file(target, "aarch64-linux-gnu-gcc"),
+        "[binaries]
+c = ['aarch64-linux-gnu-gcc']
+
+[host_machine]
+system = 'linux'
+cpu_family = 'aarch64'
+cpu = 'aarch64'
+endian = 'little'
+"
+    );
+
+    let target =
+        cfg_expr::targets::get_builtin_target_by_triple("powerpc64-unknown-linux-gnu").unwrap();
+    assert!(
+        crate::build_internal::meson_cross_file(target, "powerpc64-linux-gnu-gcc")
+            .ends_with("cpu_family = 'ppc64'\ncpu = 'powerpc64'\nendian = 'big'\n")
+    );
+
+    // Compilers with arguments are written as arrays
+    assert!(
+        crate::build_internal::meson_cross_file(target, "ccache powerpc64-linux-gnu-gcc -m64")
+            .starts_with("[binaries]\nc = ['ccache', 'powerpc64-linux-gnu-gcc', '-m64']\n")
+    );
+}
+
+#[cfg(feature = "cmake")]
+#[test]
+fn cmake_cross_defines() {
+    let target = cfg_expr::targets::get_builtin_target_by_triple("aarch64-linux-android").unwrap();
+    assert_eq!(
+        crate::build_internal::cmake_cross_defines(target),
+        vec![
+            "-DCMAKE_SYSTEM_NAME=Android".to_string(),
+            "-DCMAKE_SYSTEM_PROCESSOR=aarch64".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn build_internal_requires() {
+    let internal_dir = Path::new("src").join("tests").join("internal");
+    let built = Rc::new(Cell::new(Vec::new()));
+
+    let built_first = built.clone();
+    let built_second = built.clone();
+    let config = create_config(
+        "toml-internal-requires",
+        vec![("SYSTEM_DEPS_BUILD_INTERNAL", "always")],
+    )
+    .add_build_internal_with("testfirst", move |ctx| {
+        let mut v = built_first.take();
+        v.push("testfirst");
+        built_first.set(v);
+
+        // testsecond has been built first and can be found by testfirst
+        assert!(ctx.libraries.get_by_name("testsecond").is_some());
+        assert_eq!(ctx.pkg_config_path, vec![internal_dir]);
+        InternalPkgConfig::new().probe_with_env("testlib", &ctx.version, ctx.env)
+    })
+    .add_build_internal_with("testsecond", move |ctx| {
+        let mut v = built_second.take();
+        v.push("testsecond");
+        built_second.set(v);
+
+        assert!(ctx.pkg_config_path.is_empty());
+        InternalPkgConfig::new()
+            .pc_dir(Path::new("src").join("tests").join("internal"))
+            .probe_with_env("testinternal", &ctx.version, ctx.env)
+    });
+
+    let libraries = config.resolve().unwrap();
+    assert_eq!(built.take(), vec!["testsecond", "testfirst"]);
+    assert_eq!(libraries.get_by_name("testfirst").unwrap().version, "1.2.3");
+    assert_eq!(
+        libraries.get_by_name("testsecond").unwrap().version,
+        "2.1.0"
+    );
+}
+
+#[test]
+fn build_internal_requires_probe() {
+    // testinternaluser is looked for using pkg-config once testinternal, which it requires,
+    // has been built internally.
+    let libraries = create_config(
+        "toml-internal-requires-probe",
+        vec![("SYSTEM_DEPS_TESTINTERNAL_BUILD_INTERNAL", "always")],
+    )
+    .add_build_internal_with("testinternal", |ctx| {
+        InternalPkgConfig::new()
+            .pc_dir(Path::new("src").join("tests").join("internal"))
+            .probe_with_env("testinternal", &ctx.version, ctx.env)
+    })
+    .resolve()
+    .unwrap();
+
+    let lib = libraries.get_by_name("testinternaluser").unwrap();
+    assert_eq!(lib.version, "1.0.0");
+    assert!(lib.libs.iter().any(|l| l.name == "testinternal"));
+    assert!(lib
+        .pc_files
+        .contains(&Path::new("src/tests/internal/testinternal.pc").to_path_buf()));
+}
+
+#[test]
+fn requires_invalid() {
+    toml_err_manifest(
+        "toml-internal-requires-cycle",
+        "dependency cycle between `testfirst`, `testsecond`",
+    );
+    let location = toml_err_manifest(
+        "toml-internal-requires-unknown",
+        "package.metadata.system-deps.testfirst: required dependency `testnotthere` is not defined",
+    )
+    .unwrap();
+    assert_eq!(location.line, 2);
+}
+
+#[test]
+fn parallel_probe() {
+    for path in ["toml-good", "toml-two-libs", "toml-any-of"] {
+        let (_, serial) = toml(path, vec![("NUM_JOBS", "1")]).unwrap();
+        let (_, parallel) = toml(path, vec![("NUM_JOBS", "4")]).unwrap();
+        assert_eq!(serial.to_string(), parallel.to_string());
+    }
+
+    // Missing dependencies are reported in the same order
+    let serial = toml("toml-missing-multiple", vec![("NUM_JOBS", "1")]).unwrap_err();
+    let parallel = toml("toml-missing-multiple", vec![("NUM_JOBS", "4")]).unwrap_err();
+    assert_eq!(serial.to_string(), parallel.to_string());
+}
+
+#[test]
+fn probe_cache() {
+    let root = env::temp_dir().join(format!("system-deps-probe-cache-{}", std::process::id()));
+    let out_dir = root
+        .join("debug")
+        .join("build")
+        .join("foo-sys-1234")
+        .join("out");
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let out_dir: &'static str = Box::leak(out_dir.to_string_lossy().into_owned().into_boxed_str());
+
+    let env = vec![("SYSTEM_DEPS_PROBE_CACHE", "1"), ("OUT_DIR", out_dir)];
+    let (libraries, flags) = toml("toml-good", env.clone()).unwrap();
+
+    // The libraries are now retrieved from the cache, without running pkg-config
+    let cache_dir = root.join("debug").join("system-deps-probe-cache");
+    for entry in std::fs::read_dir(&cache_dir).unwrap() {
+        let path = entry.unwrap().path();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.replace("\"1.2.3\"", "\"1.2.4\"")).unwrap();
+    }
+    let cached = create_config("toml-good", env.clone())
+        .probe_full()
+        .unwrap();
+    let testlib = cached.get_by_name("testlib").unwrap();
+    assert_eq!(testlib.version, "1.2.4");
+    assert_eq!(testlib.requested_name, "testlib");
+    assert_eq!(
+        testlib.defines,
+        libraries.get_by_name("testlib").unwrap().defines
+    );
+    assert_eq!(
+        cached.gen_flags().unwrap().to_string(),
+        flags.to_string().replace("1.2.3", "1.2.4")
+    );
+
+    // The cache is keyed by target and pkg-config environment
+    let mut other_target = env.clone();
+    other_target.push(("TARGET", "aarch64-unknown-linux-gnu"));
+    let libraries = create_config("toml-good", other_target).resolve().unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+    let mut other_env = env.clone();
+    other_env.push(("PKG_CONFIG_SYSROOT_DIR", "/sysroot"));
+    let libraries = create_config("toml-good", other_env).resolve().unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+
+    // is only used if enabled
+    let libraries = create_config("toml-good", vec![("OUT_DIR", out_dir)])
+        .resolve()
+        .unwrap();
+    assert_eq!(libraries.get_by_name("testlib").unwrap().version, "1.2.3");
+
+    // and only caches the libraries found by pkg-config
+    assert!(create_config("toml-good", env.clone())
+        .probe_backend(FakeBackend::new())
+        .resolve()
+        .is_err());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}